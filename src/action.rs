@@ -1,13 +1,15 @@
 //! Available service actions
 
 use crate::anyhow::Context;
-use crate::{config, git_repo, github, graph_schema};
+use crate::{config, errors, git_repo, github, graph, graph_schema, metrics};
 
 use anyhow::Error;
-use log::debug;
+use log::{debug, info};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
+use std::collections::{BTreeMap, BTreeSet};
 use tempfile::tempdir;
+use tracing::instrument;
 
 const HASH_LENGTH: usize = 6;
 
@@ -19,23 +21,332 @@ pub enum ActionType {
   #[serde(alias = "disable")]
   #[serde(alias = "Block")]
   Disable,
+  #[serde(alias = "channel_add")]
+  ChannelAdd,
+  #[serde(alias = "channel_remove")]
+  ChannelRemove,
+}
+
+impl ActionType {
+  /// Return action type label used in metrics
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      ActionType::Enable => "enable",
+      ActionType::Disable => "disable",
+      ActionType::ChannelAdd => "channel_add",
+      ActionType::ChannelRemove => "channel_remove",
+    }
+  }
+
+  /// Action edits channel membership instead of blocked edges
+  pub fn edits_channels(&self) -> bool {
+    matches!(self, ActionType::ChannelAdd | ActionType::ChannelRemove)
+  }
+}
+
+/// Outcome of a performed action
+#[derive(Debug, Clone, PartialEq)]
+pub enum ActionResult {
+  /// New PR created, contains PR URL
+  Created(String),
+  /// Existing PR for the same version updated with a comment
+  Commented(String),
+  /// Existing PR for the same version with a different action closed
+  Closed(String),
+  /// Graph data already in the requested state, no PR needed
+  NothingToDo,
+  /// Graph data already in the requested state,
+  /// contains paths and contents of blocked edges files for the version
+  AlreadySatisfied { files: BTreeMap<String, String> },
+  /// Dry run, contains paths which would be changed and the update graph impact
+  DryRun {
+    created: Vec<String>,
+    modified: Vec<String>,
+    removed: Vec<String>,
+    impact: graph::Impact,
+    /// Reason the action would be rejected by the safety check unless forced
+    unsafe_reason: Option<String>,
+  },
+}
+
+impl ActionResult {
+  /// Return outcome label used in metrics
+  pub fn outcome(&self) -> &'static str {
+    match self {
+      ActionResult::Created(_) => "created_pr",
+      ActionResult::Commented(_) => "commented",
+      ActionResult::Closed(_) => "closed",
+      ActionResult::NothingToDo => "nothing_to_do",
+      ActionResult::AlreadySatisfied { .. } => "already_satisfied",
+      ActionResult::DryRun { .. } => "dry_run",
+    }
+  }
+
+  /// Return affected PR URL
+  pub fn url(&self) -> Option<&str> {
+    match self {
+      ActionResult::Created(url) | ActionResult::Commented(url) | ActionResult::Closed(url) => {
+        Some(url)
+      }
+      ActionResult::NothingToDo
+      | ActionResult::AlreadySatisfied { .. }
+      | ActionResult::DryRun { .. } => None,
+    }
+  }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Action {
   r#type: ActionType,
+  /// Release version, empty for range actions
+  #[serde(default)]
   version: String,
+  /// Act on all known releases matching the semver range, e.g. `>=4.6.1, <4.6.5`
+  #[serde(default)]
+  version_range: Option<String>,
+  /// Act on all known releases fully matching the regexp
+  #[serde(default)]
+  version_regex: Option<String>,
   title: String,
   body: String,
+  #[serde(default)]
+  target: Option<String>,
+  /// Base branch for the PR, overrides the target's base branch
+  #[serde(default)]
+  base_branch: Option<String>,
+  /// Regexp of source versions to block, all versions by default
+  #[serde(default)]
+  from: Option<String>,
+  /// Block the edge only for clusters matching the risk
+  #[serde(default)]
+  risk: Option<graph_schema::Risk>,
+  /// Unblock only the edge declaring the risk with this name
+  #[serde(default)]
+  risk_name: Option<String>,
+  /// Channel edited by channel actions, e.g. `fast-4.6`
+  #[serde(default)]
+  channel: Option<String>,
+  /// Report changes and their update graph impact without opening a PR
+  #[serde(default)]
+  dry_run: bool,
+  /// Open the PR even if the action leaves releases without an update path
+  #[serde(default)]
+  force: bool,
+  /// Why the safety check is overridden, recorded in the PR
+  #[serde(default)]
+  force_reason: Option<String>,
 }
 
 impl Action {
+  /// Return target name requested in the action
+  pub fn target(&self) -> Option<&str> {
+    self.target.as_deref()
+  }
+
+  /// Return a string identifying the requested change, used to match retried requests
+  pub fn fingerprint(&self, target: &str) -> String {
+    format!(
+      "{}:{}:{}:{}:{}:{}:{}:{}:{}:{}:{}",
+      target,
+      self.r#type.as_str(),
+      self.version,
+      self.version_range.as_deref().unwrap_or_default(),
+      self.version_regex.as_deref().unwrap_or_default(),
+      self.base_branch.as_deref().unwrap_or_default(),
+      self.from.as_deref().unwrap_or_default(),
+      self
+        .risk
+        .as_ref()
+        .map(|r| r.name.as_str())
+        .or(self.risk_name.as_deref())
+        .unwrap_or_default(),
+      self.channel.as_deref().unwrap_or_default(),
+      self.dry_run,
+      self.force
+    )
+  }
+
+  /// Return blocked edge requested by a disable action
+  pub fn blocked_edge(&self) -> graph_schema::BlockedEdge {
+    self.blocked_edge_for(&self.version)
+  }
+
+  /// Return blocked edge requested by a disable action for the version
+  pub fn blocked_edge_for(&self, version: &str) -> graph_schema::BlockedEdge {
+    graph_schema::BlockedEdge::new(
+      version,
+      self
+        .from
+        .as_deref()
+        .unwrap_or(graph_schema::ALL_VERSIONS_REGEXP),
+      self.risk.as_ref(),
+    )
+  }
+
+  /// Return release selector of range actions
+  pub fn release_selector(&self) -> Result<Option<graph_schema::ReleaseSelector>, String> {
+    match (&self.version_range, &self.version_regex) {
+      (Some(range), None) => graph_schema::ReleaseSelector::range(range).map(Some),
+      (None, Some(regex)) => graph_schema::ReleaseSelector::regex(regex).map(Some),
+      (None, None) => Ok(None),
+      (Some(_), Some(_)) => {
+        Err("only one of version_range and version_regex can be set".to_string())
+      }
+    }
+  }
+
+  /// Return a label for the selected releases, used in PR titles and logs
+  pub fn releases_label(&self) -> &str {
+    self
+      .version_range
+      .as_deref()
+      .or(self.version_regex.as_deref())
+      .unwrap_or(&self.version)
+  }
+
+  /// Validate and normalize the version, updating the title to use the normalized version
+  pub fn normalize(&mut self) -> Result<(), String> {
+    let is_range = self.version_range.is_some() || self.version_regex.is_some();
+    if is_range && self.version.is_empty() {
+      return Ok(());
+    }
+    let version = normalize_version(&self.version)?;
+    if version != self.version {
+      let raw = self.version.trim().to_string();
+      self.title = self.title.replace(&raw, &version);
+      self.version = version;
+    }
+    Ok(())
+  }
+
+  /// Return edges lifted by an enable action
+  pub fn unblock(&self) -> graph_schema::Unblock {
+    graph_schema::Unblock {
+      from: self.from.clone(),
+      risk_name: self.risk_name.clone(),
+    }
+  }
+
+  /// Check the action is well-formed, returning a description of all problems found
+  pub fn validate(&self) -> Result<(), String> {
+    let mut errors = vec![];
+    let is_range = self.version_range.is_some() || self.version_regex.is_some();
+    if is_range && !self.version.is_empty() {
+      errors.push("version can't be combined with version_range or version_regex".to_string());
+    }
+    if let Err(e) = self.release_selector() {
+      errors.push(e);
+    }
+    if let Some(base_branch) = &self.base_branch {
+      if !config::is_valid_branch_name(base_branch) {
+        errors.push(format!(
+          "base_branch {:?} is not a valid branch name",
+          base_branch
+        ));
+      }
+    }
+    match self.r#type {
+      ActionType::Disable => {
+        if let Some(from) = &self.from {
+          errors.extend(graph_schema::validate_from(from));
+        }
+        if let Some(risk) = &self.risk {
+          errors.extend(risk.validate());
+        }
+        if self.risk_name.is_some() {
+          errors.push("risk_name is only supported by enable actions".to_string());
+        }
+      }
+      ActionType::Enable => {
+        if self.risk.is_some() {
+          errors.push("risk is only supported by disable actions".to_string());
+        }
+      }
+      ActionType::ChannelAdd | ActionType::ChannelRemove => {
+        match self.channel.as_deref() {
+          None => errors.push("channel must be set".to_string()),
+          Some(channel) if !graph_schema::is_valid_channel_name(channel) => {
+            errors.push(format!("channel name {:?} is invalid", channel))
+          }
+          Some(_) => {}
+        }
+        if self.from.is_some() || self.risk.is_some() || self.risk_name.is_some() {
+          errors.push("from, risk and risk_name are not supported by channel actions".to_string());
+        }
+      }
+    }
+    if !self.r#type.edits_channels() && self.channel.is_some() {
+      errors.push("channel is only supported by channel actions".to_string());
+    }
+    match (self.force, self.force_reason.as_deref().map(str::trim)) {
+      (true, None) | (true, Some("")) => errors.push("force requires force_reason".to_string()),
+      (false, Some(_)) => errors.push("force_reason is only used with force".to_string()),
+      _ => {}
+    }
+    if errors.is_empty() {
+      Ok(())
+    } else {
+      Err(errors.join(", "))
+    }
+  }
+
   /// Return necessary data for PR - title, body
   pub fn to_pr_tuple(&self) -> (&str, &str) {
     return (self.title.as_str(), self.body.as_str());
   }
 }
 
+/// Parse release version, e.g. `4.6.1`, `4.6.0-rc.1` or `4.6.1+amd64`, and return it normalized.
+/// Surrounding whitespace and a leading `v` are dropped.
+fn normalize_version(version: &str) -> Result<String, String> {
+  let trimmed = version.trim();
+  let trimmed = trimmed
+    .strip_prefix(|c: char| c == 'v' || c == 'V')
+    .unwrap_or(trimmed);
+  let valid_chars = trimmed
+    .chars()
+    .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '+');
+  if !valid_chars || trimmed.contains("..") {
+    return Err(format!("version {:?} contains invalid characters", version));
+  }
+  semver::Version::parse(trimmed)
+    .map(|v| v.to_string())
+    .map_err(|e| {
+      format!(
+        "version {:?} is not a valid release version: {}",
+        version, e
+      )
+    })
+}
+
+/// Describe releases the action leaves without an update path
+fn stranding_message(impact: &graph::Impact) -> String {
+  let list = |releases: &[graph::StrandedRelease]| -> String {
+    releases
+      .iter()
+      .map(|r| r.to_string())
+      .collect::<Vec<String>>()
+      .join(", ")
+  };
+  let mut problems = vec![];
+  if !impact.stranded.is_empty() {
+    problems.push(format!(
+      "releases left without an update path: {}",
+      list(&impact.stranded)
+    ));
+  }
+  if !impact.unreachable_heads.is_empty() {
+    problems.push(format!(
+      "channel heads left unreachable: {}",
+      list(&impact.unreachable_heads)
+    ));
+  }
+  format!(
+    "{}; set force and force_reason to override",
+    problems.join("; ")
+  )
+}
+
 /// Generate a new branch name
 fn generate_branch_name(title: String) -> String {
   let rand_string: String = thread_rng()
@@ -50,20 +361,194 @@ fn generate_branch_name(title: String) -> String {
   format!("{}-{}", ascii_title, rand_string)
 }
 
+/// Return signer for configured commit signing method
+fn commit_signer(settings: &config::CommitSettings) -> Option<git_repo::Signer> {
+  settings
+    .signing
+    .as_ref()
+    .map(|signing| match signing.format {
+      config::SigningFormat::Gpg => git_repo::Signer::Gpg {
+        program: signing.program.clone().unwrap_or_else(|| "gpg".to_string()),
+        key: signing.key.clone(),
+      },
+      config::SigningFormat::Ssh => git_repo::Signer::Ssh {
+        program: signing
+          .program
+          .clone()
+          .unwrap_or_else(|| "ssh-keygen".to_string()),
+        key_path: signing.key.clone().into(),
+      },
+    })
+}
+
+/// Check signing keys of all targets can be used, so misconfigured signing fails on
+/// startup or reload rather than in actions.
+pub fn check_commit_signing(settings: &config::AppSettings) -> Result<(), Error> {
+  for (name, github) in settings.all_targets() {
+    if let Some(signer) = commit_signer(&github.commit) {
+      signer.check().context(format!(
+        "Commit signing of target {} is configured, but the key can't be used",
+        name
+      ))?;
+    }
+  }
+  Ok(())
+}
+
+/// Check upstream blocked edges and return current state if it already matches a disable
+/// or enable action. Files blocking the version may have any name, so all of them are checked,
+/// conventionally named ones first: an enable action is satisfied when none blocks the version.
+async fn upstream_state(
+  action: &Action,
+  token: &str,
+  settings: &config::GithubSettings,
+  base_branch: &str,
+) -> Result<Option<ActionResult>, Error> {
+  let is_edge_action = matches!(action.r#type, ActionType::Disable | ActionType::Enable);
+  if !is_edge_action || action.version.is_empty() {
+    return Ok(None);
+  }
+  let edge = action.blocked_edge();
+  let names = github::list_directory(
+    token,
+    &settings.target_organization,
+    &settings.target_repo,
+    graph_schema::BLOCKED_DIR,
+    base_branch,
+  )
+  .await?;
+  let mut names: Vec<&String> = names.iter().filter(|n| n.ends_with(".yaml")).collect();
+  names.sort_by_key(|name| !graph_schema::is_conventional_file_name(name, &action.version));
+  for name in names {
+    let path = format!("{}/{}", graph_schema::BLOCKED_DIR, name);
+    let contents = github::file_contents(
+      token,
+      &settings.target_organization,
+      &settings.target_repo,
+      &path,
+      base_branch,
+    )
+    .await?;
+    if let Some(contents) = contents {
+      match action.r#type {
+        ActionType::Disable if graph_schema::contains_edge(&contents, &edge) => {
+          let mut files = BTreeMap::new();
+          files.insert(path, contents);
+          return Ok(Some(ActionResult::AlreadySatisfied { files }));
+        }
+        // The version is blocked, the unblock is calculated after cloning
+        ActionType::Enable if graph_schema::blocks_version(&contents, &action.version) => {
+          return Ok(None);
+        }
+        _ => {}
+      }
+    }
+  }
+  if action.r#type == ActionType::Enable {
+    return Ok(Some(ActionResult::AlreadySatisfied {
+      files: BTreeMap::new(),
+    }));
+  }
+  Ok(None)
+}
+
+/// Return commit author, committer and message trailers.
+/// The bot is always the committer, the client may be the author if configured.
+fn commit_attribution(
+  settings: &config::CommitSettings,
+  client: Option<config::ClientIdentity>,
+) -> (git_repo::Identity, git_repo::Identity, Vec<String>) {
+  let bot = git_repo::Identity {
+    name: settings.author_name.clone(),
+    email: settings.author_email.clone(),
+  };
+  let client = client.map(|c| git_repo::Identity {
+    name: c.name,
+    email: c.email,
+  });
+  let author = match &client {
+    Some(client) if settings.author_from_client => client.clone(),
+    _ => bot.clone(),
+  };
+
+  let mut trailers = settings.trailers.clone();
+  if let Some(client) = &client {
+    if settings.co_author_from_client && *client != author {
+      trailers.push(format!("Co-authored-by: {}", client));
+    }
+  }
+  if settings.signoff {
+    trailers.push(format!("Signed-off-by: {}", author));
+    if author != bot {
+      trailers.push(format!("Signed-off-by: {}", bot));
+    }
+  }
+  (author, bot, trailers)
+}
+
 /// Create a PR from specified action
+// Warn level keeps action fields on log lines at default verbosity
+#[instrument(
+  level = "warn",
+  skip(action, settings, client),
+  fields(
+    target = target,
+    version = action.releases_label(),
+    action_type = action.r#type.as_str(),
+    branch = tracing::field::Empty
+  )
+)]
 pub async fn perform_action(
   action: Action,
+  target: &str,
+  settings: config::GithubSettings,
+  client: Option<config::ClientIdentity>,
+) -> Result<ActionResult, Error> {
+  let action_type = action.r#type.as_str();
+  let result = run_action(action, target, settings, client).await;
+  let outcome = match &result {
+    Ok(r) => r.outcome(),
+    Err(_) => "failed",
+  };
+  metrics::ACTIONS_TOTAL
+    .with_label_values(&[target, action_type, outcome])
+    .inc();
+  result
+}
+
+async fn run_action(
+  mut action: Action,
+  target: &str,
   settings: config::GithubSettings,
-) -> Result<String, Error> {
+  client: Option<config::ClientIdentity>,
+) -> Result<ActionResult, Error> {
   debug!("Performing action {:?}", action);
+  let base_branch = action
+    .base_branch
+    .clone()
+    .unwrap_or_else(|| settings.base_branch.clone());
 
+  let token = settings.read_token()?;
   let mut github_repo = github::GithubRepo::new(
-    settings.token,
+    token.clone(),
     settings.target_organization.as_str(),
     settings.target_repo.as_str(),
   );
 
-  let maybe_pr_id = github_repo.has_open_pr_for(action.version.as_str()).await?;
+  // Range and channel actions never match an open PR, PR titles only carry a version.
+  // Dry runs must not comment on or close existing PRs.
+  let dedup_version = if action.r#type.edits_channels() || action.dry_run {
+    ""
+  } else {
+    action.version.as_str()
+  };
+  let maybe_pr_id = if dedup_version.is_empty() {
+    None
+  } else {
+    github_repo
+      .has_open_pr_for(dedup_version, base_branch.as_str())
+      .await?
+  };
   if maybe_pr_id.is_some() {
     let pr_id = maybe_pr_id.unwrap();
     debug!("Updating existing PR ID {:?}", pr_id);
@@ -80,6 +565,7 @@ pub async fn perform_action(
         return github_repo
           .close_pr(pr_id)
           .await
+          .map(ActionResult::Closed)
           .map_err(|e| anyhow!("Couldn't close PR: {}", e));
       }
       Ok(action_type) => {
@@ -88,38 +574,196 @@ pub async fn perform_action(
           return github_repo
             .close_pr(pr_id)
             .await
+            .map(ActionResult::Closed)
             .map_err(|e| anyhow!("Couldn't close PR: {}", e));
         } else {
-          return Ok(pr_url);
+          return Ok(ActionResult::Commented(pr_url));
         }
       }
     }
   }
 
+  // Checked after open PRs are reconciled, so a PR for a stale action is still closed
+  match upstream_state(&action, &token, &settings, &base_branch).await {
+    Ok(Some(result)) => {
+      debug!("Upstream already in requested state");
+      return Ok(result);
+    }
+    Ok(None) => {}
+    Err(e) => debug!("Couldn't check upstream state, proceeding: {:#}", e),
+  }
+
   let tmpdir = tempdir().context("Failed to create tempdir")?;
   let path = tmpdir.path().to_path_buf();
 
+  let timer = metrics::OPERATION_DURATION
+    .with_label_values(&[target, "clone"])
+    .start_timer();
   let mut gitrepo = git_repo::GitRepo::new(
     settings.fork_organization.as_str(),
     settings.fork_repo.as_str(),
     &path,
   )
   .context("Failed to clone the repo")?;
+  timer.observe_duration();
+
+  let timer = metrics::OPERATION_DURATION
+    .with_label_values(&[target, "fetch"])
+    .start_timer();
   gitrepo
     .fetch_from_upstream(
-      settings.fork_organization.as_str(),
-      settings.fork_repo.as_str(),
+      settings.target_organization.as_str(),
+      settings.target_repo.as_str(),
+      base_branch.as_str(),
     )
     .context("Failed to fetch repo upstream")?;
+  timer.observe_duration();
+
+  match graph_schema::count_blocked_edge_files(&path) {
+    Ok(count) => metrics::BLOCKED_EDGE_FILES
+      .with_label_values(&[target])
+      .set(count as i64),
+    Err(e) => debug!("Failed to count blocked edges files: {}", e),
+  }
+
+  let versions = match action.release_selector().map_err(|e| anyhow!(e))? {
+    Some(selector) => {
+      let versions = graph_schema::matching_releases(&path, &selector)
+        .context("Failed to resolve releases from channels")?;
+      if versions.is_empty() {
+        bail!("No known releases match {}", action.releases_label());
+      }
+      debug!("Resolved {} to {:?}", action.releases_label(), versions);
+      action.body = format!(
+        "{}\n\nVersions matching `{}`:\n{}",
+        action.body,
+        action.releases_label(),
+        versions
+          .iter()
+          .map(|v| format!("- {}", v))
+          .collect::<Vec<String>>()
+          .join("\n")
+      );
+      versions
+    }
+    None => vec![action.version.clone()],
+  };
+
+  let candidates = match &settings.graph_snapshot_path {
+    Some(snapshot) => {
+      Some(graph::read_snapshot(snapshot).context("Failed to read graph snapshot")?)
+    }
+    None => None,
+  };
+  let violations_before =
+    graph_schema::validate_tree(&path).context("Failed to validate graph data")?;
+  if !violations_before.is_empty() {
+    let messages: Vec<String> = violations_before.iter().map(|v| v.to_string()).collect();
+    info!(
+      "Graph data has violations before the action: {:?}",
+      messages
+    );
+  }
+  let graph_before =
+    graph::Graph::load(&path, candidates.as_ref()).context("Failed to build update graph")?;
 
   debug!("Calculating action");
-  match action.r#type {
-    ActionType::Disable => graph_schema::block_edge(&path, action.version.clone()),
-    ActionType::Enable => graph_schema::unblock_edge(&path, action.version.clone()),
+  let channel = action.channel.clone().unwrap_or_default();
+  let mut changes = graph_schema::Changes::default();
+  for version in &versions {
+    let version_changes = match action.r#type {
+      ActionType::Disable => graph_schema::block_edge(&path, &action.blocked_edge_for(version)),
+      ActionType::Enable => graph_schema::unblock_edge(&path, version.clone(), &action.unblock()),
+      ActionType::ChannelAdd => graph_schema::add_to_channel(&path, &channel, version),
+      ActionType::ChannelRemove => graph_schema::remove_from_channel(&path, &channel, version),
+    }
+    .with_context(|| format!("Failed to perform action for {}", version))?;
+    changes.extend(version_changes);
+  }
+  debug!("Changed files: {:?}", changes);
+  if changes.is_empty() {
+    let mut files: BTreeMap<String, String> = changes
+      .remaining
+      .iter()
+      .map(|file| (file.path.display().to_string(), file.contents.clone()))
+      .collect();
+    if action.r#type.edits_channels() {
+      let channel_path = graph_schema::channel_file(&channel);
+      let contents =
+        std::fs::read_to_string(path.join(&channel_path)).context("Failed to read channel file")?;
+      files.insert(channel_path.display().to_string(), contents);
+    }
+    return Ok(ActionResult::AlreadySatisfied { files });
+  }
+  if action.r#type == ActionType::Enable && !changes.remaining.is_empty() {
+    let remaining: Vec<String> = changes
+      .remaining
+      .iter()
+      .map(|file| format!("- {}: {}", file.path.display(), file.edge))
+      .collect();
+    info!("Still blocked after unblocking: {:?}", remaining);
+    action.body = format!(
+      "{}\n\nStill blocked after this change:\n{}",
+      action.body,
+      remaining.join("\n")
+    );
+  }
+
+  // Only fail on violations introduced by the action, upstream problems are fixed separately
+  let violations: Vec<String> = graph_schema::validate_tree(&path)
+    .context("Failed to validate graph data")?
+    .iter()
+    .filter(|violation| !violations_before.iter().any(|v| v.same_problem(violation)))
+    .map(|violation| violation.to_string())
+    .collect();
+  if !violations.is_empty() {
+    return Err(errors::AppError::InvalidGraphData(violations).into());
+  }
+
+  let graph_after =
+    graph::Graph::load(&path, candidates.as_ref()).context("Failed to build update graph")?;
+  let blocked: BTreeSet<semver::Version> = match action.r#type {
+    ActionType::Disable => versions
+      .iter()
+      .filter_map(|v| semver::Version::parse(v).ok())
+      .collect(),
+    _ => BTreeSet::new(),
+  };
+  let impact = graph::Impact::between(&graph_before, &graph_after, &blocked);
+  info!("{}", impact);
+  action.body = format!("{}\n\n{}", action.body, impact);
+  let unsafe_reason = if impact.strands_releases() {
+    Some(stranding_message(&impact))
+  } else {
+    None
+  };
+  if action.dry_run {
+    let paths = |paths: &[std::path::PathBuf]| -> Vec<String> {
+      paths.iter().map(|p| p.display().to_string()).collect()
+    };
+    return Ok(ActionResult::DryRun {
+      created: paths(&changes.created),
+      modified: paths(&changes.modified),
+      removed: paths(&changes.removed),
+      impact,
+      unsafe_reason,
+    });
+  }
+  if let Some(message) = unsafe_reason {
+    if !action.force {
+      return Err(errors::AppError::UnsafeAction(message).into());
+    }
+    let reason = action.force_reason.clone().unwrap_or_default();
+    info!("Safety check overridden: {}", reason);
+    action.body = format!(
+      "{}\n\nSafety check overridden: {}",
+      action.body,
+      reason.trim()
+    );
   }
-  .context("Failed to perform action")?;
 
   let branch = generate_branch_name(action.title.clone());
+  tracing::Span::current().record("branch", branch.as_str());
   debug!("Generated branch {}", branch.clone());
   gitrepo
     .switch_to(&branch.to_string())
@@ -130,17 +774,312 @@ pub async fn perform_action(
     settings.fork_organization.clone(),
     settings.fork_repo.clone(),
   );
-  let commit_message = format!("{}\n{}", action.title, action.body);
+  let tree = match gitrepo
+    .stage(&changes.updated(), &changes.removed)
+    .context("Failed to stage changes")?
+  {
+    Some(tree) => tree,
+    None => return Ok(ActionResult::NothingToDo),
+  };
+  let (author, committer, trailers) = commit_attribution(&settings.commit, client);
+  let commit_message = git_repo::commit_message(&action.title, &action.body, &trailers);
   gitrepo
-    .commit(&branch, commit_message)
+    .commit(
+      &branch,
+      tree,
+      commit_message,
+      &author,
+      &committer,
+      commit_signer(&settings.commit).as_ref(),
+    )
     .context("Failed to commit changes")?;
+  let timer = metrics::OPERATION_DURATION
+    .with_label_values(&[target, "push"])
+    .start_timer();
   gitrepo
     .push_to_remote(&branch)
     .context("Failed to push to remote")?;
+  timer.observe_duration();
 
   debug!("Creating new PR");
-  github_repo
-    .create_pr(settings.fork_organization.as_str(), &branch, action)
+  let timer = metrics::OPERATION_DURATION
+    .with_label_values(&[target, "create_pr"])
+    .start_timer();
+  let pr_url = github_repo
+    .create_pr(
+      settings.fork_organization.as_str(),
+      &branch,
+      base_branch.as_str(),
+      action,
+    )
     .await
-    .map_err(|e| anyhow!("Couldn't create PR: {}", e))
+    .map_err(|e| anyhow!("Couldn't create PR: {}", e))?;
+  timer.observe_duration();
+  Ok(ActionResult::Created(pr_url))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn client() -> config::ClientIdentity {
+    config::ClientIdentity {
+      id: "monitor".to_string(),
+      name: "Monitor".to_string(),
+      email: "monitor@example.com".to_string(),
+    }
+  }
+
+  #[test]
+  fn normalize_versions() {
+    assert_eq!(normalize_version("4.6.1").unwrap(), "4.6.1");
+    assert_eq!(normalize_version(" v4.6.1 ").unwrap(), "4.6.1");
+    assert_eq!(normalize_version("4.6.0-rc.1").unwrap(), "4.6.0-rc.1");
+    assert_eq!(
+      normalize_version("4.6.0-0.nightly-2020-09-10-145837").unwrap(),
+      "4.6.0-0.nightly-2020-09-10-145837"
+    );
+    assert_eq!(normalize_version("4.6.1+amd64").unwrap(), "4.6.1+amd64");
+    assert!(normalize_version("4.6").is_err());
+    assert!(normalize_version("").is_err());
+    assert!(normalize_version("../../etc/passwd").is_err());
+    assert!(normalize_version("4.6.1/../../x").is_err());
+    assert!(normalize_version("4.6.1-rc..1").is_err());
+  }
+
+  #[test]
+  fn validate_range_action() {
+    let action: Action = serde_json::from_value(json!({
+      "type": "disable",
+      "version_range": ">=4.6.1, <4.6.5",
+      "title": "Block edges 4.6.1 - 4.6.4",
+      "body": "",
+    }))
+    .unwrap();
+    assert!(action.validate().is_ok());
+    assert_eq!(action.releases_label(), ">=4.6.1, <4.6.5");
+
+    let action: Action = serde_json::from_value(json!({
+      "type": "disable",
+      "version": "4.6.1",
+      "version_range": ">=4.6.1, <4.6.5",
+      "version_regex": "4\\.6\\..*",
+      "title": "Block edges",
+      "body": "",
+    }))
+    .unwrap();
+    assert_eq!(
+      action.validate().unwrap_err(),
+      "version can't be combined with version_range or version_regex, \
+       only one of version_range and version_regex can be set"
+    );
+  }
+
+  #[test]
+  fn validate_channel_action() {
+    let action: Action = serde_json::from_value(json!({
+      "type": "channel_add",
+      "version": "4.6.3",
+      "channel": "fast-4.6",
+      "title": "Add 4.6.3 to fast-4.6",
+      "body": "",
+    }))
+    .unwrap();
+    assert!(action.validate().is_ok());
+    assert!(action.r#type.edits_channels());
+
+    let action: Action = serde_json::from_value(json!({
+      "type": "channel_remove",
+      "version": "4.6.3",
+      "from": "4\\.5\\..*",
+      "title": "Remove 4.6.3 from stable-4.6",
+      "body": "",
+    }))
+    .unwrap();
+    assert_eq!(
+      action.validate().unwrap_err(),
+      "channel must be set, from, risk and risk_name are not supported by channel actions"
+    );
+
+    let action: Action = serde_json::from_value(json!({
+      "type": "disable",
+      "version": "4.6.3",
+      "channel": "fast-4.6",
+      "title": "Block edge 4.6.3",
+      "body": "",
+    }))
+    .unwrap();
+    assert_eq!(
+      action.validate().unwrap_err(),
+      "channel is only supported by channel actions"
+    );
+  }
+
+  #[test]
+  fn validate_base_branch() {
+    let action: Action = serde_json::from_value(json!({
+      "type": "disable",
+      "version": "4.6.3",
+      "title": "Block edge 4.6.3",
+      "body": "",
+      "base_branch": "release-4.6",
+    }))
+    .unwrap();
+    assert!(action.validate().is_ok());
+
+    let action: Action = serde_json::from_value(json!({
+      "type": "disable",
+      "version": "4.6.3",
+      "title": "Block edge 4.6.3",
+      "body": "",
+      "base_branch": "../master",
+    }))
+    .unwrap();
+    assert_eq!(
+      action.validate().unwrap_err(),
+      "base_branch \"../master\" is not a valid branch name"
+    );
+  }
+
+  #[test]
+  fn validate_forced_action() {
+    let action: Action = serde_json::from_value(json!({
+      "type": "disable",
+      "version": "4.6.3",
+      "title": "Block edge 4.6.3",
+      "body": "",
+      "force": true,
+      "force_reason": "4.6.3 breaks clusters, 4.6.4 is on the way",
+    }))
+    .unwrap();
+    assert!(action.validate().is_ok());
+
+    let action: Action = serde_json::from_value(json!({
+      "type": "disable",
+      "version": "4.6.3",
+      "title": "Block edge 4.6.3",
+      "body": "",
+      "force": true,
+      "force_reason": " ",
+    }))
+    .unwrap();
+    assert_eq!(
+      action.validate().unwrap_err(),
+      "force requires force_reason"
+    );
+  }
+
+  #[test]
+  fn stranding_message_lists_releases() {
+    let release = |version: &str| graph::StrandedRelease {
+      version: version.to_string(),
+      channel: "fast-4.6".to_string(),
+    };
+    let impact = graph::Impact {
+      stranded: vec![release("4.6.1"), release("4.6.2")],
+      unreachable_heads: vec![release("4.6.3")],
+      ..Default::default()
+    };
+    assert_eq!(
+      stranding_message(&impact),
+      "releases left without an update path: 4.6.1 in fast-4.6, 4.6.2 in fast-4.6; \
+       channel heads left unreachable: 4.6.3 in fast-4.6; set force and force_reason to override"
+    );
+  }
+
+  #[test]
+  fn normalize_action_title() {
+    let mut action: Action = serde_json::from_value(json!({
+      "type": "disable",
+      "version": "v4.5.1",
+      "title": "Block edge v4.5.1",
+      "body": "",
+    }))
+    .unwrap();
+    action.normalize().unwrap();
+    assert_eq!(action.version, "4.5.1");
+    assert_eq!(action.title, "Block edge 4.5.1");
+  }
+
+  #[test]
+  fn validate_conditional_action() {
+    let action: Action = serde_json::from_value(json!({
+      "type": "disable",
+      "version": "4.5.1",
+      "title": "Block edge 4.5.1",
+      "body": "",
+      "from": "4\\.4\\..*",
+      "risk": {
+        "name": "SomeRisk",
+        "url": "https://bugzilla.redhat.com/show_bug.cgi?id=1",
+        "message": "Clusters on AWS may fail to update.",
+        "matchingRules": [{"type": "Always"}]
+      }
+    }))
+    .unwrap();
+    assert!(action.validate().is_ok());
+    assert_eq!(action.blocked_edge().risk().unwrap().name, "SomeRisk");
+
+    let action: Action = serde_json::from_value(json!({
+      "type": "disable",
+      "version": "4.5.1",
+      "title": "Block edge 4.5.1",
+      "body": "",
+      "risk": {
+        "name": "SomeRisk",
+        "url": "https://bugzilla.redhat.com/show_bug.cgi?id=1",
+        "message": "",
+        "matchingRules": []
+      }
+    }))
+    .unwrap();
+    assert_eq!(
+      action.validate().unwrap_err(),
+      "risk message must not be empty, risk must have at least one matching rule"
+    );
+  }
+
+  #[test]
+  fn commit_attribution_default() {
+    let settings = config::CommitSettings::default();
+    let (author, committer, trailers) = commit_attribution(&settings, Some(client()));
+    assert_eq!(author, committer);
+    assert_eq!(author.name, settings.author_name);
+    assert!(trailers.is_empty());
+  }
+
+  #[test]
+  fn commit_attribution_client_author_signoff() {
+    let settings = config::CommitSettings {
+      author_from_client: true,
+      signoff: true,
+      trailers: vec!["Reviewed-by: Reviewer <reviewer@example.com>".to_string()],
+      ..Default::default()
+    };
+    let (author, committer, trailers) = commit_attribution(&settings, Some(client()));
+    assert_eq!(author.email, "monitor@example.com");
+    assert_eq!(committer.name, settings.author_name);
+    assert_eq!(
+      trailers,
+      vec![
+        "Reviewed-by: Reviewer <reviewer@example.com>".to_string(),
+        "Signed-off-by: Monitor <monitor@example.com>".to_string(),
+        format!("Signed-off-by: {}", committer),
+      ]
+    );
+  }
+
+  #[test]
+  fn commit_attribution_co_author() {
+    let settings = config::CommitSettings {
+      co_author_from_client: true,
+      ..Default::default()
+    };
+    let (author, _, trailers) = commit_attribution(&settings, Some(client()));
+    assert_eq!(author.name, settings.author_name);
+    assert_eq!(
+      trailers,
+      vec!["Co-authored-by: Monitor <monitor@example.com>".to_string()]
+    );
+  }
 }
@@ -1,8 +1,11 @@
 //! Application settings for graph-breaker.
 use anyhow::{Context, Result};
 pub use smart_default::SmartDefault;
+use std::collections::{BTreeMap, BTreeSet};
 use std::io::Read;
 use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
 use std::{fs, io, path};
 use structopt::StructOpt;
 
@@ -33,6 +36,59 @@ where
   Ok(Some(verbosity))
 }
 
+/// Config keys holding secrets, redacted when the config file is printed.
+const SECRET_KEYS: &[&str] = &["token", "client_auth_token"];
+const REDACTED: &str = "<redacted>";
+
+/// Name of the target configured in `[github]` section
+pub const DEFAULT_TARGET: &str = "default";
+
+/// Read a secret from a file, an environment variable or the literal value, in this order.
+///
+/// The file is read on every call, so rotated secrets are picked up without a restart.
+pub fn read_secret(
+  literal: &str,
+  path: &Option<PathBuf>,
+  env_var: &Option<String>,
+) -> Result<String> {
+  if let Some(path) = path {
+    let secret =
+      fs::read_to_string(path).context(format!("failed to read secret from {}", path.display()))?;
+    return Ok(secret.trim().to_string());
+  }
+  if let Some(env_var) = env_var {
+    return std::env::var(env_var).context(format!("failed to read secret from ${}", env_var));
+  }
+  Ok(literal.to_string())
+}
+
+/// Replace values of secret keys at any nesting level in TOML content.
+pub fn redact_secrets(content: &str) -> String {
+  let mut value = match content.parse::<toml::Value>() {
+    Ok(value) => value,
+    // Secrets can't be located in content which doesn't parse, so it isn't shown
+    Err(_) => return "content not shown, not valid TOML".to_string(),
+  };
+  redact_value(&mut value);
+  toml::to_string(&value).unwrap_or_else(|_| "content not shown, not representable".to_string())
+}
+
+fn redact_value(value: &mut toml::Value) {
+  match value {
+    toml::Value::Table(table) => {
+      for (key, value) in table.iter_mut() {
+        if SECRET_KEYS.contains(&key.as_str()) {
+          *value = toml::Value::String(REDACTED.to_string());
+        } else {
+          redact_value(value);
+        }
+      }
+    }
+    toml::Value::Array(values) => values.iter_mut().for_each(redact_value),
+    _ => {}
+  }
+}
+
 /// Try to merge configuration options into runtime settings.
 ///
 /// This consumes a generic configuration object, trying to merge its options
@@ -55,46 +111,490 @@ pub struct AppSettings {
 
   /// Github options.
   pub github: GithubSettings,
+
+  /// Additional named targets.
+  pub targets: BTreeMap<String, GithubSettings>,
+
+  /// Tracing options.
+  pub telemetry: TelemetrySettings,
+
+  /// Config file settings were read from, if any.
+  pub config_path: Option<PathBuf>,
 }
 
 impl AppSettings {
-  /// Lookup all optional configs, merge them with defaults, and
-  /// transform into valid runtime settings.
-  pub fn assemble() -> Result<Self> {
+  /// Merge options with defaults, in increasing order of precedence:
+  /// config file (if any), environment variables, CLI flags.
+  pub fn assemble_from(cli_opts: CliOptions) -> Result<Self> {
     let defaults = Self::default();
     let mut cfg = defaults;
 
-    // Source options.
-    let cli_opts = CliOptions::from_args();
-
-    // File options are required
-    let file_opts = match FileOptions::read_filepath(cli_opts.config_path.clone()) {
-      Ok(opts) => opts,
-      Err(e) => return Err(e),
-    };
+    // Config file is optional when all settings are passed via env or CLI
+    if let Some(config_path) = &cli_opts.config_path {
+      let file_opts = FileOptions::read_filepath(config_path)?;
+      cfg.try_merge(file_opts)?;
+      cfg.config_path = Some(PathBuf::from(config_path));
+    }
 
-    // Combine options into a single config.
+    // Environment variables are handled by clap, CLI flags take precedence over them
     cfg.try_merge(cli_opts)?;
-    cfg.try_merge(file_opts.clone())?;
-
-    cfg.service = file_opts.service;
-    cfg.github = file_opts.github;
 
     // Validate and convert to settings.
+    cfg.validate()?;
     Ok(cfg)
   }
+
+  /// Check settings for values which would only fail once an action is performed.
+  /// All problems are reported at once.
+  pub fn validate(&self) -> Result<()> {
+    let mut errors = vec![];
+
+    if self.service.port == 0 {
+      errors.push("service.port must not be 0".to_string());
+    }
+    if self.service.readiness_interval_secs == 0 {
+      errors.push("service.readiness_interval_secs must not be 0".to_string());
+    }
+    if self.service.reload_interval_secs == 0 {
+      errors.push("service.reload_interval_secs must not be 0".to_string());
+    }
+    match self.service.read_client_auth_token() {
+      Ok(token) if token.is_empty() => errors.push("client auth token is empty".to_string()),
+      Ok(_) => {}
+      Err(e) => errors.push(format!("{:#}", e)),
+    }
+    for (id, client) in &self.service.clients {
+      match client.read_token() {
+        Ok(token) if token.is_empty() => {
+          errors.push(format!("service.clients.{}.token is empty", id))
+        }
+        Ok(_) => {}
+        Err(e) => errors.push(format!("service.clients.{}: {:#}", id, e)),
+      }
+      if client.name.is_empty() || client.email.is_empty() {
+        errors.push(format!("service.clients.{} name and email must be set", id));
+      }
+      if client.common_name.is_some() && !self.service.requires_client_certificate() {
+        errors.push(format!(
+          "service.clients.{}.common_name requires service.tls.client_ca_path",
+          id
+        ));
+      }
+    }
+    if let Some(tls) = &self.service.tls {
+      let mut files = vec![("service.tls.cert_path", &tls.cert_path)];
+      files.push(("service.tls.key_path", &tls.key_path));
+      if let Some(client_ca_path) = &tls.client_ca_path {
+        files.push(("service.tls.client_ca_path", client_ca_path));
+      } else if !tls.client_allowed_cns.is_empty() {
+        errors.push("service.tls.client_allowed_cns requires client_ca_path".to_string());
+      }
+      for (key, path) in files {
+        if !path.is_file() {
+          errors.push(format!("{} {} is not a file", key, path.display()));
+        }
+      }
+    }
+
+    if self.targets.contains_key(DEFAULT_TARGET) {
+      errors.push(format!("target name {} is reserved", DEFAULT_TARGET));
+    }
+    for (name, github) in self.all_targets() {
+      if name != DEFAULT_TARGET && !is_valid_target_name(name) {
+        errors.push(format!(
+          "target name {:?} must only contain alphanumerics, '-' and '_'",
+          name
+        ));
+      }
+      github.validate(&target_prefix(name), &mut errors);
+    }
+
+    if !errors.is_empty() {
+      bail!("invalid configuration:\n  - {}", errors.join("\n  - "));
+    }
+    Ok(())
+  }
+
+  /// Return flattened settings as key-value pairs, optionally with secrets redacted.
+  pub fn entries(&self, redact: bool) -> Vec<(String, String)> {
+    let secret = |value: &str| match (redact, value.is_empty()) {
+      (true, false) => REDACTED.to_string(),
+      _ => value.to_string(),
+    };
+    let tls = self.service.tls.clone().unwrap_or_default();
+    vec![
+      ("verbosity", self.verbosity.to_string()),
+      ("service.address", self.service.address.to_string()),
+      ("service.port", self.service.port.to_string()),
+      (
+        "service.client_auth_token",
+        secret(&self.service.client_auth_token),
+      ),
+      (
+        "service.client_auth_token_path",
+        format!("{:?}", self.service.client_auth_token_path),
+      ),
+      (
+        "service.client_auth_token_env",
+        format!("{:?}", self.service.client_auth_token_env),
+      ),
+      ("service.tls", self.service.tls.is_some().to_string()),
+      ("service.tls.cert_path", tls.cert_path.display().to_string()),
+      ("service.tls.key_path", tls.key_path.display().to_string()),
+      (
+        "service.tls.client_ca_path",
+        format!("{:?}", tls.client_ca_path),
+      ),
+      (
+        "service.tls.client_allowed_cns",
+        format!("{:?}", tls.client_allowed_cns),
+      ),
+      (
+        "service.readiness_interval_secs",
+        self.service.readiness_interval_secs.to_string(),
+      ),
+      (
+        "service.reload_interval_secs",
+        self.service.reload_interval_secs.to_string(),
+      ),
+      (
+        "telemetry.otlp_endpoint",
+        format!("{:?}", self.telemetry.otlp_endpoint),
+      ),
+      (
+        "telemetry.service_name",
+        self.telemetry.service_name.clone(),
+      ),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v))
+    .chain(self.service.clients.iter().flat_map(|(id, client)| {
+      vec![
+        ("token", secret(&client.token)),
+        ("token_path", format!("{:?}", client.token_path)),
+        ("token_env", format!("{:?}", client.token_env)),
+        ("name", client.name.clone()),
+        ("email", client.email.clone()),
+        ("common_name", format!("{:?}", client.common_name)),
+      ]
+      .into_iter()
+      .map(move |(k, v)| (format!("service.clients.{}.{}", id, k), v))
+    }))
+    .chain(
+      self
+        .all_targets()
+        .into_iter()
+        .flat_map(|(name, github)| github.entries(&target_prefix(name), redact)),
+    )
+    .collect()
+  }
+
+  /// Return all targets by name, including the default one.
+  pub fn all_targets(&self) -> Vec<(&str, &GithubSettings)> {
+    let mut targets = vec![(DEFAULT_TARGET, &self.github)];
+    targets.extend(self.targets.iter().map(|(name, t)| (name.as_str(), t)));
+    targets
+  }
+
+  /// Return target by name, default target is used when name is not set.
+  pub fn target(&self, name: Option<&str>) -> Result<&GithubSettings> {
+    match name {
+      None | Some(DEFAULT_TARGET) => Ok(&self.github),
+      Some(name) => self
+        .targets
+        .get(name)
+        .ok_or_else(|| anyhow!("unknown target {}", name)),
+    }
+  }
+
+  /// Return descriptions of settings which differ in `other`, with secrets redacted.
+  pub fn changes(&self, other: &AppSettings) -> Vec<String> {
+    let old: BTreeMap<String, String> = self.entries(false).into_iter().collect();
+    let new: BTreeMap<String, String> = other.entries(false).into_iter().collect();
+    let new_redacted: BTreeMap<String, String> = other.entries(true).into_iter().collect();
+    let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    let unset = "<unset>".to_string();
+    keys
+      .into_iter()
+      .filter(|key| old.get(*key) != new.get(*key))
+      .map(|key| {
+        let is_secret = key
+          .rsplit('.')
+          .next()
+          .map_or(false, |k| SECRET_KEYS.contains(&k));
+        if is_secret {
+          format!("{} changed", key)
+        } else {
+          format!(
+            "{}: {} -> {}",
+            key,
+            old.get(key).unwrap_or(&unset),
+            new_redacted.get(key).unwrap_or(&unset)
+          )
+        }
+      })
+      .collect()
+  }
+}
+
+/// Return prefix for config keys of the named target.
+fn target_prefix(name: &str) -> String {
+  match name {
+    DEFAULT_TARGET => "github".to_string(),
+    _ => format!("targets.{}", name),
+  }
+}
+
+/// Check branch name is safe to use as a refspec.
+pub fn is_valid_branch_name(name: &str) -> bool {
+  !name.is_empty()
+    && !name.starts_with('-')
+    && !name.contains("..")
+    && name
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || "-_./".contains(c))
 }
 
-/// CLI configuration flags
-#[derive(Debug, StructOpt)]
+fn is_valid_target_name(name: &str) -> bool {
+  !name.is_empty()
+    && name
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Settings shared between request handlers, which can be atomically replaced on reload.
+/// Handlers keep using the settings they started with until they finish.
+#[derive(Debug, Default)]
+pub struct SharedSettings(RwLock<Arc<AppSettings>>);
+
+impl SharedSettings {
+  pub fn new(settings: AppSettings) -> Self {
+    SharedSettings(RwLock::new(Arc::new(settings)))
+  }
+
+  /// Return current settings.
+  pub fn current(&self) -> Arc<AppSettings> {
+    self.0.read().unwrap().clone()
+  }
+
+  /// Replace current settings, returning the previous ones.
+  pub fn replace(&self, settings: AppSettings) -> Arc<AppSettings> {
+    std::mem::replace(&mut *self.0.write().unwrap(), Arc::new(settings))
+  }
+}
+
+/// CLI configuration flags, each setting can also be set via `GRAPH_BREAKER_*` env var
+#[derive(Debug, Clone, StructOpt)]
 pub struct CliOptions {
   /// Verbosity level
   #[structopt(short = "v", parse(from_occurrences))]
   pub verbosity: u8,
 
   /// Path to configuration file
-  #[structopt(short = "c")]
-  pub config_path: String,
+  #[structopt(short = "c", env = "GRAPH_BREAKER_CONFIG")]
+  pub config_path: Option<String>,
+
+  #[structopt(subcommand)]
+  pub command: Option<Command>,
+
+  /// Listening address for the main service
+  #[structopt(long = "address", env = "GRAPH_BREAKER_ADDRESS")]
+  pub address: Option<IpAddr>,
+
+  /// Listening port for the main service
+  #[structopt(long = "port", env = "GRAPH_BREAKER_PORT")]
+  pub port: Option<u16>,
+
+  /// Client auth token
+  #[structopt(long = "client-auth-token", env = "GRAPH_BREAKER_CLIENT_AUTH_TOKEN")]
+  pub client_auth_token: Option<String>,
+
+  /// Path to file with client auth token
+  #[structopt(
+    long = "client-auth-token-path",
+    env = "GRAPH_BREAKER_CLIENT_AUTH_TOKEN_PATH",
+    parse(from_os_str)
+  )]
+  pub client_auth_token_path: Option<PathBuf>,
+
+  /// Environment variable with client auth token
+  #[structopt(
+    long = "client-auth-token-env",
+    env = "GRAPH_BREAKER_CLIENT_AUTH_TOKEN_ENV"
+  )]
+  pub client_auth_token_env: Option<String>,
+
+  /// Interval between readiness checks, in seconds
+  #[structopt(
+    long = "readiness-interval-secs",
+    env = "GRAPH_BREAKER_READINESS_INTERVAL_SECS"
+  )]
+  pub readiness_interval_secs: Option<u64>,
+
+  /// Interval between config file change checks, in seconds
+  #[structopt(
+    long = "reload-interval-secs",
+    env = "GRAPH_BREAKER_RELOAD_INTERVAL_SECS"
+  )]
+  pub reload_interval_secs: Option<u64>,
+
+  /// Path to TLS certificate chain, enables TLS
+  #[structopt(
+    long = "tls-cert-path",
+    env = "GRAPH_BREAKER_TLS_CERT_PATH",
+    parse(from_os_str)
+  )]
+  pub tls_cert_path: Option<PathBuf>,
+
+  /// Path to TLS private key
+  #[structopt(
+    long = "tls-key-path",
+    env = "GRAPH_BREAKER_TLS_KEY_PATH",
+    parse(from_os_str)
+  )]
+  pub tls_key_path: Option<PathBuf>,
+
+  /// Path to CA bundle for client certificate authentication
+  #[structopt(
+    long = "tls-client-ca-path",
+    env = "GRAPH_BREAKER_TLS_CLIENT_CA_PATH",
+    parse(from_os_str)
+  )]
+  pub tls_client_ca_path: Option<PathBuf>,
+
+  /// Allowed client certificate common names, comma-separated
+  #[structopt(
+    long = "tls-client-allowed-cns",
+    env = "GRAPH_BREAKER_TLS_CLIENT_ALLOWED_CNS",
+    raw(use_delimiter = "true")
+  )]
+  pub tls_client_allowed_cns: Vec<String>,
+
+  /// Github token
+  #[structopt(long = "github-token", env = "GRAPH_BREAKER_GITHUB_TOKEN")]
+  pub github_token: Option<String>,
+
+  /// Path to file with github token
+  #[structopt(
+    long = "github-token-path",
+    env = "GRAPH_BREAKER_GITHUB_TOKEN_PATH",
+    parse(from_os_str)
+  )]
+  pub github_token_path: Option<PathBuf>,
+
+  /// Environment variable with github token
+  #[structopt(long = "github-token-env", env = "GRAPH_BREAKER_GITHUB_TOKEN_ENV")]
+  pub github_token_env: Option<String>,
+
+  /// Target github org
+  #[structopt(
+    long = "target-organization",
+    env = "GRAPH_BREAKER_TARGET_ORGANIZATION"
+  )]
+  pub target_organization: Option<String>,
+
+  /// Target github repo
+  #[structopt(long = "target-repo", env = "GRAPH_BREAKER_TARGET_REPO")]
+  pub target_repo: Option<String>,
+
+  /// Fork github org/user
+  #[structopt(long = "fork-organization", env = "GRAPH_BREAKER_FORK_ORGANIZATION")]
+  pub fork_organization: Option<String>,
+
+  /// Fork github repo
+  #[structopt(long = "fork-repo", env = "GRAPH_BREAKER_FORK_REPO")]
+  pub fork_repo: Option<String>,
+
+  /// Upstream branch to fetch and open PRs against
+  #[structopt(long = "base-branch", env = "GRAPH_BREAKER_BASE_BRANCH")]
+  pub base_branch: Option<String>,
+
+  /// Bot name used as commit author and committer
+  #[structopt(long = "commit-author-name", env = "GRAPH_BREAKER_COMMIT_AUTHOR_NAME")]
+  pub commit_author_name: Option<String>,
+
+  /// Bot email used as commit author and committer
+  #[structopt(
+    long = "commit-author-email",
+    env = "GRAPH_BREAKER_COMMIT_AUTHOR_EMAIL"
+  )]
+  pub commit_author_email: Option<String>,
+
+  /// Use the calling client as commit author, `true` or `false`
+  #[structopt(
+    long = "commit-author-from-client",
+    env = "GRAPH_BREAKER_COMMIT_AUTHOR_FROM_CLIENT"
+  )]
+  pub commit_author_from_client: Option<bool>,
+
+  /// Add a `Co-authored-by` trailer for the calling client, `true` or `false`
+  #[structopt(
+    long = "commit-co-author-from-client",
+    env = "GRAPH_BREAKER_COMMIT_CO_AUTHOR_FROM_CLIENT"
+  )]
+  pub commit_co_author_from_client: Option<bool>,
+
+  /// Add `Signed-off-by` trailers, `true` or `false`
+  #[structopt(long = "commit-signoff", env = "GRAPH_BREAKER_COMMIT_SIGNOFF")]
+  pub commit_signoff: Option<bool>,
+
+  /// Extra commit trailer, may be repeated; newline-separated in the env var
+  #[structopt(
+    long = "commit-trailer",
+    env = "GRAPH_BREAKER_COMMIT_TRAILERS",
+    raw(
+      number_of_values = "1",
+      use_delimiter = "true",
+      value_delimiter = r#""\n""#
+    )
+  )]
+  pub commit_trailers: Vec<String>,
+
+  /// Commit signature format, `gpg` or `ssh`, enables signing
+  #[structopt(
+    long = "commit-signing-format",
+    env = "GRAPH_BREAKER_COMMIT_SIGNING_FORMAT"
+  )]
+  pub commit_signing_format: Option<SigningFormat>,
+
+  /// GPG key ID or path to SSH private key used for commit signing
+  #[structopt(long = "commit-signing-key", env = "GRAPH_BREAKER_COMMIT_SIGNING_KEY")]
+  pub commit_signing_key: Option<String>,
+
+  /// Commit signing program, `gpg` or `ssh-keygen` by default
+  #[structopt(
+    long = "commit-signing-program",
+    env = "GRAPH_BREAKER_COMMIT_SIGNING_PROGRAM"
+  )]
+  pub commit_signing_program: Option<String>,
+
+  /// Cincinnati graph JSON limiting possible updates in graph impact reports
+  #[structopt(
+    long = "graph-snapshot-path",
+    env = "GRAPH_BREAKER_GRAPH_SNAPSHOT_PATH",
+    parse(from_os_str)
+  )]
+  pub graph_snapshot_path: Option<PathBuf>,
+
+  /// OTLP collector endpoint
+  #[structopt(long = "otlp-endpoint", env = "GRAPH_BREAKER_OTLP_ENDPOINT")]
+  pub otlp_endpoint: Option<String>,
+
+  /// Service name reported in exported spans
+  #[structopt(long = "service-name", env = "GRAPH_BREAKER_SERVICE_NAME")]
+  pub service_name: Option<String>,
+}
+
+/// Alternative run modes
+#[derive(Debug, Clone, StructOpt)]
+pub enum Command {
+  /// Validate config, print effective config with secrets redacted and exit
+  #[structopt(name = "check-config")]
+  CheckConfig {
+    /// Also check github token and repository access
+    #[structopt(long = "probe-github")]
+    probe_github: bool,
+  },
 }
 
 impl MergeOptions<CliOptions> for AppSettings {
@@ -106,6 +606,87 @@ impl MergeOptions<CliOptions> for AppSettings {
       _ => log::LevelFilter::Trace,
     };
 
+    assign_if_some!(self.service.address, opts.address);
+    assign_if_some!(self.service.port, opts.port);
+    assign_if_some!(self.service.client_auth_token, opts.client_auth_token);
+    if opts.client_auth_token_path.is_some() {
+      self.service.client_auth_token_path = opts.client_auth_token_path;
+    }
+    if opts.client_auth_token_env.is_some() {
+      self.service.client_auth_token_env = opts.client_auth_token_env;
+    }
+    assign_if_some!(
+      self.service.readiness_interval_secs,
+      opts.readiness_interval_secs
+    );
+    assign_if_some!(self.service.reload_interval_secs, opts.reload_interval_secs);
+
+    if opts.tls_cert_path.is_some()
+      || opts.tls_key_path.is_some()
+      || opts.tls_client_ca_path.is_some()
+      || !opts.tls_client_allowed_cns.is_empty()
+    {
+      let tls = self.service.tls.get_or_insert_with(TlsSettings::default);
+      assign_if_some!(tls.cert_path, opts.tls_cert_path);
+      assign_if_some!(tls.key_path, opts.tls_key_path);
+      if opts.tls_client_ca_path.is_some() {
+        tls.client_ca_path = opts.tls_client_ca_path;
+      }
+      if !opts.tls_client_allowed_cns.is_empty() {
+        tls.client_allowed_cns = opts.tls_client_allowed_cns;
+      }
+    }
+
+    assign_if_some!(self.github.token, opts.github_token);
+    if opts.github_token_path.is_some() {
+      self.github.token_path = opts.github_token_path;
+    }
+    if opts.github_token_env.is_some() {
+      self.github.token_env = opts.github_token_env;
+    }
+    assign_if_some!(self.github.target_organization, opts.target_organization);
+    assign_if_some!(self.github.target_repo, opts.target_repo);
+    assign_if_some!(self.github.fork_organization, opts.fork_organization);
+    assign_if_some!(self.github.fork_repo, opts.fork_repo);
+    assign_if_some!(self.github.base_branch, opts.base_branch);
+    assign_if_some!(self.github.commit.author_name, opts.commit_author_name);
+    assign_if_some!(self.github.commit.author_email, opts.commit_author_email);
+    assign_if_some!(
+      self.github.commit.author_from_client,
+      opts.commit_author_from_client
+    );
+    assign_if_some!(
+      self.github.commit.co_author_from_client,
+      opts.commit_co_author_from_client
+    );
+    assign_if_some!(self.github.commit.signoff, opts.commit_signoff);
+    if !opts.commit_trailers.is_empty() {
+      self.github.commit.trailers = opts.commit_trailers;
+    }
+    if opts.commit_signing_format.is_some()
+      || opts.commit_signing_key.is_some()
+      || opts.commit_signing_program.is_some()
+    {
+      let signing = self
+        .github
+        .commit
+        .signing
+        .get_or_insert_with(SigningSettings::default);
+      assign_if_some!(signing.format, opts.commit_signing_format);
+      assign_if_some!(signing.key, opts.commit_signing_key);
+      if opts.commit_signing_program.is_some() {
+        signing.program = opts.commit_signing_program;
+      }
+    }
+    if opts.graph_snapshot_path.is_some() {
+      self.github.graph_snapshot_path = opts.graph_snapshot_path;
+    }
+
+    if opts.otlp_endpoint.is_some() {
+      self.telemetry.otlp_endpoint = opts.otlp_endpoint;
+    }
+    assign_if_some!(self.telemetry.service_name, opts.service_name);
+
     Ok(())
   }
 }
@@ -118,14 +699,25 @@ pub struct FileOptions {
   pub verbosity: Option<log::LevelFilter>,
 
   /// App options.
+  #[serde(default)]
   pub service: ServiceSettings,
 
   /// Github options.
+  #[serde(default)]
   pub github: GithubSettings,
+
+  /// Additional named targets.
+  #[serde(default)]
+  pub targets: BTreeMap<String, GithubSettings>,
+
+  /// Tracing options.
+  #[serde(default)]
+  pub telemetry: TelemetrySettings,
 }
 
 /// Service settings
 #[derive(Debug, SmartDefault, Deserialize, Clone)]
+#[serde(default)]
 pub struct ServiceSettings {
   /// Listening address for the main service.
   #[default(IpAddr::V4(Ipv4Addr::LOCALHOST))]
@@ -137,14 +729,58 @@ pub struct ServiceSettings {
 
   /// Client auth token
   pub client_auth_token: String,
+
+  /// Path to file with client auth token, overrides `client_auth_token`
+  pub client_auth_token_path: Option<PathBuf>,
+
+  /// Environment variable with client auth token, overrides `client_auth_token`
+  pub client_auth_token_env: Option<String>,
+
+  /// Additional API clients by ID, each with its own token and identity
+  pub clients: BTreeMap<String, ClientSettings>,
+
+  /// TLS options, plain HTTP is served when unset.
+  pub tls: Option<TlsSettings>,
+
+  /// Interval between readiness checks, in seconds
+  #[default(60)]
+  pub readiness_interval_secs: u64,
+
+  /// Interval between config file change checks, in seconds
+  #[default(30)]
+  pub reload_interval_secs: u64,
+}
+
+/// TLS settings
+#[derive(Debug, SmartDefault, Deserialize, Clone)]
+pub struct TlsSettings {
+  /// Path to PEM-encoded certificate chain
+  pub cert_path: PathBuf,
+
+  /// Path to PEM-encoded private key (PKCS#8 or RSA)
+  pub key_path: PathBuf,
+
+  /// Path to PEM-encoded CA bundle, enables client certificate authentication
+  pub client_ca_path: Option<PathBuf>,
+
+  /// Client certificate common names allowed to connect, any CN if empty
+  #[serde(default)]
+  pub client_allowed_cns: Vec<String>,
 }
 
 /// Github settings
 #[derive(Debug, SmartDefault, Deserialize, Clone)]
+#[serde(default)]
 pub struct GithubSettings {
-  /// Path to github token
+  /// Github token
   pub token: String,
 
+  /// Path to file with github token, overrides `token`
+  pub token_path: Option<PathBuf>,
+
+  /// Environment variable with github token, overrides `token`
+  pub token_env: Option<String>,
+
   /// Target github org
   #[default("openshift")]
   pub target_organization: String,
@@ -160,6 +796,289 @@ pub struct GithubSettings {
   /// Fork github repo
   #[default("cincinnati-graph-data")]
   pub fork_repo: String,
+
+  /// Upstream branch to fetch and open PRs against
+  #[default("master")]
+  pub base_branch: String,
+
+  /// Commit options
+  pub commit: CommitSettings,
+
+  /// Cincinnati graph JSON limiting possible updates in graph impact reports,
+  /// any newer release in the same channel is considered otherwise
+  pub graph_snapshot_path: Option<PathBuf>,
+}
+
+/// Commit settings
+#[derive(Debug, SmartDefault, Deserialize, Clone)]
+#[serde(default)]
+pub struct CommitSettings {
+  /// Bot name, used as committer and author
+  #[default("Openshift OTA Bot")]
+  pub author_name: String,
+
+  /// Bot email, used as committer and author
+  #[default("vrutkovs@redhat.com")]
+  pub author_email: String,
+
+  /// Use the calling client as commit author, the bot remains the committer
+  pub author_from_client: bool,
+
+  /// Add `Co-authored-by` trailer for the calling client
+  pub co_author_from_client: bool,
+
+  /// Add `Signed-off-by` trailers for commit author and committer
+  pub signoff: bool,
+
+  /// Additional trailers appended to commit messages, e.g. "Reviewed-by: Name <email>"
+  pub trailers: Vec<String>,
+
+  /// Commit signing options, commits are unsigned when unset
+  pub signing: Option<SigningSettings>,
+}
+
+/// Commit signature format
+#[derive(Debug, SmartDefault, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SigningFormat {
+  #[default]
+  Gpg,
+  Ssh,
+}
+
+impl std::str::FromStr for SigningFormat {
+  type Err = String;
+
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    match s {
+      "gpg" => Ok(SigningFormat::Gpg),
+      "ssh" => Ok(SigningFormat::Ssh),
+      _ => Err(format!(
+        "unknown signing format {:?}, expected gpg or ssh",
+        s
+      )),
+    }
+  }
+}
+
+/// Commit signing settings
+#[derive(Debug, Default, Deserialize, Clone)]
+pub struct SigningSettings {
+  /// Signature format, `gpg` or `ssh`
+  pub format: SigningFormat,
+
+  /// GPG key ID or path to SSH private key
+  pub key: String,
+
+  /// Signing program, `gpg` or `ssh-keygen` by default
+  pub program: Option<String>,
+}
+
+/// API client, identified by its auth token
+#[derive(Debug, SmartDefault, Deserialize, Clone)]
+#[serde(default)]
+pub struct ClientSettings {
+  /// Client auth token
+  pub token: String,
+
+  /// Path to file with client auth token, overrides `token`
+  pub token_path: Option<PathBuf>,
+
+  /// Environment variable with client auth token, overrides `token`
+  pub token_env: Option<String>,
+
+  /// Client name, used in commit attribution
+  pub name: String,
+
+  /// Client email, used in commit attribution
+  pub email: String,
+
+  /// Client certificate common name identifying this client when client certificates are required
+  pub common_name: Option<String>,
+}
+
+impl ClientSettings {
+  /// Return current client auth token.
+  pub fn read_token(&self) -> Result<String> {
+    read_secret(&self.token, &self.token_path, &self.token_env)
+  }
+
+  /// Return identity of the client with the given ID.
+  fn identity(&self, id: &str) -> ClientIdentity {
+    ClientIdentity {
+      id: id.to_string(),
+      name: self.name.clone(),
+      email: self.email.clone(),
+    }
+  }
+}
+
+/// Identity of the authenticated client, stored in request extensions
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientIdentity {
+  /// Client ID from config
+  pub id: String,
+  pub name: String,
+  pub email: String,
+}
+
+/// Tracing settings
+#[derive(Debug, SmartDefault, Deserialize, Clone)]
+#[serde(default)]
+pub struct TelemetrySettings {
+  /// OTLP collector endpoint, spans are not exported when unset
+  pub otlp_endpoint: Option<String>,
+
+  /// Service name reported in exported spans
+  #[default("graph-breaker")]
+  pub service_name: String,
+}
+
+impl ServiceSettings {
+  /// Return current client auth token.
+  pub fn read_client_auth_token(&self) -> Result<String> {
+    read_secret(
+      &self.client_auth_token,
+      &self.client_auth_token_path,
+      &self.client_auth_token_env,
+    )
+  }
+
+  /// Find the additional client identified by the token.
+  pub fn client_by_token(&self, token: &str) -> Result<Option<ClientIdentity>> {
+    for (id, client) in &self.clients {
+      if token == client.read_token()? {
+        return Ok(Some(client.identity(id)));
+      }
+    }
+    Ok(None)
+  }
+
+  /// Find the additional client identified by the client certificate common name.
+  pub fn client_by_common_name(&self, common_name: &str) -> Option<ClientIdentity> {
+    self
+      .clients
+      .iter()
+      .find(|(_, client)| client.common_name.as_deref() == Some(common_name))
+      .map(|(id, client)| client.identity(id))
+  }
+
+  /// Whether clients must present a certificate on action endpoints.
+  pub fn requires_client_certificate(&self) -> bool {
+    self
+      .tls
+      .as_ref()
+      .map_or(false, |tls| tls.client_ca_path.is_some())
+  }
+}
+
+impl GithubSettings {
+  /// Return current github token.
+  pub fn read_token(&self) -> Result<String> {
+    read_secret(&self.token, &self.token_path, &self.token_env)
+  }
+
+  /// Return flattened settings with keys under `prefix`.
+  fn entries(&self, prefix: &str, redact: bool) -> Vec<(String, String)> {
+    let token = match (redact, self.token.is_empty()) {
+      (true, false) => REDACTED.to_string(),
+      _ => self.token.clone(),
+    };
+    vec![
+      ("token", token),
+      ("token_path", format!("{:?}", self.token_path)),
+      ("token_env", format!("{:?}", self.token_env)),
+      ("target_organization", self.target_organization.clone()),
+      ("target_repo", self.target_repo.clone()),
+      ("fork_organization", self.fork_organization.clone()),
+      ("fork_repo", self.fork_repo.clone()),
+      ("base_branch", self.base_branch.clone()),
+      ("commit.author_name", self.commit.author_name.clone()),
+      ("commit.author_email", self.commit.author_email.clone()),
+      (
+        "commit.author_from_client",
+        self.commit.author_from_client.to_string(),
+      ),
+      (
+        "commit.co_author_from_client",
+        self.commit.co_author_from_client.to_string(),
+      ),
+      ("commit.signoff", self.commit.signoff.to_string()),
+      ("commit.trailers", format!("{:?}", self.commit.trailers)),
+      ("commit.signing", format!("{:?}", self.commit.signing)),
+      (
+        "graph_snapshot_path",
+        format!("{:?}", self.graph_snapshot_path),
+      ),
+    ]
+    .into_iter()
+    .map(|(k, v)| (format!("{}.{}", prefix, k), v))
+    .collect()
+  }
+
+  /// Append problems with settings under `prefix` to `errors`.
+  fn validate(&self, prefix: &str, errors: &mut Vec<String>) {
+    match self.read_token() {
+      Ok(token) if token.is_empty() => errors.push(format!("{}.token is empty", prefix)),
+      Ok(_) => {}
+      Err(e) => errors.push(format!("{}: {:#}", prefix, e)),
+    }
+    for (key, value) in &[
+      ("target_organization", &self.target_organization),
+      ("target_repo", &self.target_repo),
+      ("fork_organization", &self.fork_organization),
+      ("fork_repo", &self.fork_repo),
+    ] {
+      if value.is_empty() {
+        errors.push(format!("{}.{} is empty", prefix, key));
+      }
+    }
+    if self.commit.author_name.is_empty() || self.commit.author_email.is_empty() {
+      errors.push(format!(
+        "{}.commit author name and email must be set",
+        prefix
+      ));
+    }
+    for trailer in &self.commit.trailers {
+      if !trailer.contains(": ") || trailer.contains('\n') {
+        errors.push(format!(
+          "{}.commit trailer {:?} must be a single \"Key: value\" line",
+          prefix, trailer
+        ));
+      }
+    }
+    if let Some(signing) = &self.commit.signing {
+      if signing.key.is_empty() {
+        errors.push(format!("{}.commit.signing.key is empty", prefix));
+      } else if signing.format == SigningFormat::Ssh && !Path::new(&signing.key).is_file() {
+        errors.push(format!(
+          "{}.commit.signing.key {} is not a file",
+          prefix, signing.key
+        ));
+      }
+    }
+    if let Some(snapshot) = &self.graph_snapshot_path {
+      if !snapshot.is_file() {
+        errors.push(format!(
+          "{}.graph_snapshot_path {} is not a file",
+          prefix,
+          snapshot.display()
+        ));
+      }
+    }
+    if !is_valid_branch_name(&self.base_branch) {
+      errors.push(format!(
+        "{}.base_branch {:?} is not a valid branch name",
+        prefix, self.base_branch
+      ));
+    }
+    if self.target_organization == self.fork_organization && self.target_repo == self.fork_repo {
+      errors.push(format!(
+        "{} target and fork repositories are identical",
+        prefix
+      ));
+    }
+  }
 }
 
 impl FileOptions {
@@ -178,7 +1097,9 @@ impl FileOptions {
     let cfg = toml::from_slice(&content).context(format!(
       "failed to parse config file {}:\n{}",
       cfg_path.as_ref().display(),
-      std::str::from_utf8(&content).unwrap_or("file not decodable")
+      std::str::from_utf8(&content)
+        .map(redact_secrets)
+        .unwrap_or_else(|_| "file not decodable".to_string())
     ))?;
 
     Ok(cfg)
@@ -188,6 +1109,343 @@ impl FileOptions {
 impl MergeOptions<FileOptions> for AppSettings {
   fn try_merge(&mut self, opts: FileOptions) -> Result<()> {
     assign_if_some!(self.verbosity, opts.verbosity);
+    self.service = opts.service;
+    self.github = opts.github;
+    self.targets = opts.targets;
+    self.telemetry = opts.telemetry;
     Ok(())
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Write;
+  use tempfile::tempdir;
+
+  #[test]
+  fn redact_secrets_in_content() {
+    let content =
+      "[service]\nclient_auth_token = \"foo\"\n[github]\ntoken= \"bar\"\ntarget_repo = \"repo\"";
+    let redacted = redact_secrets(content);
+    assert!(!redacted.contains("foo"));
+    assert!(!redacted.contains("bar"));
+    assert!(redacted.contains("target_repo = \"repo\""));
+  }
+
+  #[test]
+  fn redact_secrets_in_nested_values() {
+    let content = r#"
+      [service]
+      clients = { monitor = { token = "foo" } }
+      [targets.okd]
+      token = "bar"
+      [[extra]]
+      token = "baz"
+      names = [{ token = "qux" }]
+    "#;
+    let redacted = redact_secrets(content);
+    for secret in &["foo", "bar", "baz", "qux"] {
+      assert!(!redacted.contains(secret), "{} in {}", secret, redacted);
+    }
+    assert_eq!(redacted.matches(REDACTED).count(), 4);
+
+    let redacted = redact_secrets("token = \"foo\"\n[github\n");
+    assert!(!redacted.contains("foo"));
+  }
+
+  #[test]
+  fn read_secret_from_file() {
+    let tmpdir = tempdir().unwrap();
+    let path = tmpdir.path().join("token");
+    let mut f = fs::File::create(&path).unwrap();
+    f.write_all(b"secret\n").unwrap();
+    drop(f);
+
+    let secret = read_secret("literal", &Some(path.clone()), &None).unwrap();
+    assert_eq!(secret, "secret");
+
+    // Rotated file is read again
+    fs::write(&path, "rotated").unwrap();
+    let secret = read_secret("literal", &Some(path), &None).unwrap();
+    assert_eq!(secret, "rotated");
+  }
+
+  #[test]
+  fn cli_overrides_file() {
+    let tmpdir = tempdir().unwrap();
+    let path = tmpdir.path().join("config.toml");
+    fs::write(
+      &path,
+      "[service]\nport = 9090\nclient_auth_token = \"foo\"\n[github]\ntoken = \"bar\"\ntarget_repo = \"file-repo\"\n",
+    )
+    .unwrap();
+
+    let cli_opts = CliOptions::from_iter(vec![
+      "graph-breaker",
+      "-c",
+      path.to_str().unwrap(),
+      "--target-repo",
+      "cli-repo",
+      "--fork-organization",
+      "cli-org",
+    ]);
+    let settings = AppSettings::assemble_from(cli_opts).unwrap();
+    assert_eq!(settings.service.port, 9090);
+    assert_eq!(settings.service.client_auth_token, "foo");
+    assert_eq!(settings.github.target_repo, "cli-repo");
+    assert_eq!(settings.github.fork_organization, "cli-org");
+    assert_eq!(settings.github.target_organization, "openshift");
+  }
+
+  #[test]
+  fn cli_commit_attribution() {
+    let cli_opts = CliOptions::from_iter(vec![
+      "graph-breaker",
+      "--client-auth-token",
+      "foo",
+      "--github-token",
+      "bar",
+      "--commit-author-from-client",
+      "true",
+      "--commit-signoff",
+      "false",
+      "--commit-trailer",
+      "Reviewed-by: Reviewer <reviewer@example.com>",
+      "--commit-trailer",
+      "Bug: 1234",
+    ]);
+    let settings = AppSettings::assemble_from(cli_opts).unwrap();
+    assert!(settings.github.commit.author_from_client);
+    assert!(!settings.github.commit.co_author_from_client);
+    assert!(!settings.github.commit.signoff);
+    assert_eq!(
+      settings.github.commit.trailers,
+      vec![
+        "Reviewed-by: Reviewer <reviewer@example.com>".to_string(),
+        "Bug: 1234".to_string()
+      ]
+    );
+  }
+
+  #[test]
+  fn cli_commit_signing() {
+    let cli_opts = CliOptions::from_iter(vec![
+      "graph-breaker",
+      "--client-auth-token",
+      "foo",
+      "--github-token",
+      "bar",
+      "--commit-signing-key",
+      "0123456789ABCDEF",
+    ]);
+    let settings = AppSettings::assemble_from(cli_opts).unwrap();
+    let signing = settings.github.commit.signing.unwrap();
+    assert_eq!(signing.format, SigningFormat::Gpg);
+    assert_eq!(signing.key, "0123456789ABCDEF");
+    assert_eq!(signing.program, None);
+
+    let cli_opts = CliOptions::from_iter(vec![
+      "graph-breaker",
+      "--client-auth-token",
+      "foo",
+      "--github-token",
+      "bar",
+      "--commit-signing-format",
+      "ssh",
+    ]);
+    let err = format!("{:#}", AppSettings::assemble_from(cli_opts).unwrap_err());
+    assert!(
+      err.contains("github.commit.signing.key is empty"),
+      "{}",
+      err
+    );
+    assert!(
+      CliOptions::from_iter_safe(vec!["graph-breaker", "--commit-signing-format", "x509"]).is_err()
+    );
+  }
+
+  #[test]
+  fn cli_graph_snapshot() {
+    let tmpdir = tempdir().unwrap();
+    let snapshot = tmpdir.path().join("graph.json");
+    fs::write(&snapshot, "{\"nodes\": [], \"edges\": []}").unwrap();
+
+    let cli_opts = CliOptions::from_iter(vec![
+      "graph-breaker",
+      "--client-auth-token",
+      "foo",
+      "--github-token",
+      "bar",
+      "--graph-snapshot-path",
+      snapshot.to_str().unwrap(),
+    ]);
+    let settings = AppSettings::assemble_from(cli_opts).unwrap();
+    assert_eq!(settings.github.graph_snapshot_path, Some(snapshot));
+  }
+
+  #[test]
+  fn config_file_is_optional() {
+    let cli_opts = CliOptions::from_iter(vec![
+      "graph-breaker",
+      "--port",
+      "9091",
+      "--client-auth-token",
+      "foo",
+      "--github-token",
+      "bar",
+    ]);
+    let settings = AppSettings::assemble_from(cli_opts).unwrap();
+    assert_eq!(settings.service.port, 9091);
+    assert_eq!(settings.github.token, "bar");
+  }
+
+  #[test]
+  fn changes_redact_secrets() {
+    let old = AppSettings::default();
+    let mut new = old.clone();
+    new.github.token = "secret".to_string();
+    new.github.target_repo = "other".to_string();
+
+    let changes = old.changes(&new);
+    assert_eq!(changes.len(), 2);
+    assert!(changes.contains(&"github.token changed".to_string()));
+    assert!(changes.contains(&"github.target_repo: cincinnati-graph-data -> other".to_string()));
+    assert!(!changes.iter().any(|c| c.contains("secret")));
+  }
+
+  fn valid_settings() -> AppSettings {
+    let mut settings = AppSettings::default();
+    settings.service.client_auth_token = "foo".to_string();
+    settings.github.token = "bar".to_string();
+    settings
+  }
+
+  #[test]
+  fn validate_ok() {
+    assert!(valid_settings().validate().is_ok());
+  }
+
+  #[test]
+  fn validate_reports_all_errors() {
+    let mut settings = valid_settings();
+    settings.service.port = 0;
+    settings.github.token = "".to_string();
+    settings.github.fork_organization = settings.github.target_organization.clone();
+    settings.github.fork_repo = settings.github.target_repo.clone();
+
+    let err = format!("{:#}", settings.validate().unwrap_err());
+    assert!(err.contains("service.port must not be 0"));
+    assert!(err.contains("github.token is empty"));
+    assert!(err.contains("target and fork repositories are identical"));
+  }
+
+  #[test]
+  fn validate_missing_files() {
+    let mut settings = valid_settings();
+    settings.github.token_path = Some(PathBuf::from("/nonexistent/token"));
+    settings.service.tls = Some(TlsSettings {
+      cert_path: PathBuf::from("/nonexistent/tls.crt"),
+      key_path: PathBuf::from("/nonexistent/tls.key"),
+      ..Default::default()
+    });
+
+    let err = format!("{:#}", settings.validate().unwrap_err());
+    assert!(err.contains("/nonexistent/token"));
+    assert!(err.contains("service.tls.cert_path"));
+    assert!(err.contains("service.tls.key_path"));
+  }
+
+  #[test]
+  fn named_targets() {
+    let mut settings = valid_settings();
+    let mut okd = settings.github.clone();
+    okd.target_organization = "openshift".to_string();
+    okd.target_repo = "okd-graph-data".to_string();
+    settings.targets.insert("okd".to_string(), okd);
+
+    assert!(settings.validate().is_ok());
+    assert_eq!(
+      settings.target(None).unwrap().target_repo,
+      "cincinnati-graph-data"
+    );
+    assert_eq!(
+      settings.target(Some("default")).unwrap().target_repo,
+      "cincinnati-graph-data"
+    );
+    assert_eq!(
+      settings.target(Some("okd")).unwrap().target_repo,
+      "okd-graph-data"
+    );
+    assert!(settings.target(Some("staging")).is_err());
+    assert!(settings.entries(true).contains(&(
+      "targets.okd.target_repo".to_string(),
+      "okd-graph-data".to_string()
+    )));
+  }
+
+  #[test]
+  fn branch_names() {
+    assert!(is_valid_branch_name("master"));
+    assert!(is_valid_branch_name("release-4.6"));
+    assert!(is_valid_branch_name("release/4.6"));
+    assert!(!is_valid_branch_name(""));
+    assert!(!is_valid_branch_name("-f"));
+    assert!(!is_valid_branch_name("main..other"));
+    assert!(!is_valid_branch_name("main branch"));
+  }
+
+  #[test]
+  fn authenticate_clients() {
+    let mut settings = valid_settings();
+    settings.service.clients.insert(
+      "monitor".to_string(),
+      ClientSettings {
+        token: "baz".to_string(),
+        name: "Monitor".to_string(),
+        email: "monitor@example.com".to_string(),
+        ..Default::default()
+      },
+    );
+    assert!(settings.validate().is_ok());
+
+    let identity = settings.service.client_by_token("baz").unwrap().unwrap();
+    assert_eq!(identity.id, "monitor");
+    assert_eq!(identity.email, "monitor@example.com");
+    assert_eq!(settings.service.client_by_token("foo").unwrap(), None);
+    assert_eq!(settings.service.client_by_token("qux").unwrap(), None);
+  }
+
+  #[test]
+  fn authenticate_clients_by_common_name() {
+    let mut settings = valid_settings();
+    settings.service.clients.insert(
+      "monitor".to_string(),
+      ClientSettings {
+        token: "baz".to_string(),
+        name: "Monitor".to_string(),
+        email: "monitor@example.com".to_string(),
+        common_name: Some("cincinnati-monitor".to_string()),
+        ..Default::default()
+      },
+    );
+    let err = settings.validate().unwrap_err();
+    assert!(format!("{:#}", err)
+      .contains("service.clients.monitor.common_name requires service.tls.client_ca_path"));
+
+    let identity = settings
+      .service
+      .client_by_common_name("cincinnati-monitor")
+      .unwrap();
+    assert_eq!(identity.id, "monitor");
+    assert_eq!(identity.name, "Monitor");
+    assert_eq!(settings.service.client_by_common_name("monitor"), None);
+    assert!(!settings.service.requires_client_certificate());
+  }
+
+  #[test]
+  fn read_secret_literal() {
+    let secret = read_secret("literal", &None, &None).unwrap();
+    assert_eq!(secret, "literal");
+  }
+}
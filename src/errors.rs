@@ -1,3 +1,5 @@
+use crate::request_id::RequestId;
+
 use actix_web::{http, HttpResponse};
 use thiserror::Error;
 
@@ -19,16 +21,31 @@ pub enum AppError {
   /// Error performing action
   #[error("action failed")]
   ActionFailed(String),
+
+  /// Idempotency key already used for another or a running request
+  #[error("idempotency key conflict")]
+  IdempotencyConflict(String),
+
+  /// Action would leave releases without an update path
+  #[error("unsafe action")]
+  UnsafeAction(String),
+
+  /// Action would leave graph data invalid, contains all violations found
+  #[error("invalid graph data")]
+  InvalidGraphData(Vec<String>),
 }
 
 impl AppError {
-  /// Return the HTTP JSON error response.
-  pub fn as_json_error(&self) -> HttpResponse {
+  /// Return the HTTP JSON error response, including the request ID if known.
+  pub fn as_json_error(&self, request_id: Option<&RequestId>) -> HttpResponse {
     let code = self.status_code();
-    let json_body = json!({
+    let mut json_body = json!({
         "kind": self.kind(),
         "value": self.value(),
     });
+    if let Some(request_id) = request_id {
+      json_body["request_id"] = json!(request_id.0);
+    }
     HttpResponse::build(code).json(json_body)
   }
 
@@ -39,6 +56,9 @@ impl AppError {
       AppError::InvalidAction(_) => http::StatusCode::BAD_REQUEST,
       AppError::InvalidGithubToken() => http::StatusCode::INTERNAL_SERVER_ERROR,
       AppError::ActionFailed(_) => http::StatusCode::INTERNAL_SERVER_ERROR,
+      AppError::IdempotencyConflict(_) => http::StatusCode::CONFLICT,
+      AppError::UnsafeAction(_) => http::StatusCode::UNPROCESSABLE_ENTITY,
+      AppError::InvalidGraphData(_) => http::StatusCode::UNPROCESSABLE_ENTITY,
     }
   }
 
@@ -49,6 +69,9 @@ impl AppError {
       AppError::InvalidAction(_) => "invalid_action",
       AppError::InvalidGithubToken() => "invalid_github_token",
       AppError::ActionFailed(_) => "action_failed",
+      AppError::IdempotencyConflict(_) => "idempotency_conflict",
+      AppError::UnsafeAction(_) => "unsafe_action",
+      AppError::InvalidGraphData(_) => "invalid_graph_data",
     };
     kind.to_string()
   }
@@ -57,16 +80,26 @@ impl AppError {
   pub fn value(&self) -> String {
     let error_msg = format!("{}", self);
     match self {
-      AppError::InvalidAction(msg) | AppError::ActionFailed(msg) => {
+      AppError::InvalidAction(msg)
+      | AppError::ActionFailed(msg)
+      | AppError::IdempotencyConflict(msg)
+      | AppError::UnsafeAction(msg) => {
         format!("{}: {}", error_msg, msg)
       }
+      AppError::InvalidGraphData(violations) => {
+        format!("{}: {}", error_msg, violations.join("; "))
+      }
       _ => error_msg,
     }
   }
 }
 
 impl actix_web::error::ResponseError for AppError {
+  fn status_code(&self) -> http::StatusCode {
+    AppError::status_code(self)
+  }
+
   fn error_response(&self) -> HttpResponse {
-    self.as_json_error()
+    self.as_json_error(None)
   }
 }
@@ -1,24 +1,129 @@
 use log::debug;
 use std::env;
+use std::io::Write;
 use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use tracing::instrument;
 
 use git2::build::{CheckoutBuilder, RepoBuilder};
 use git2::{
-  Cred, Error, FetchOptions, IndexAddOption, ObjectType, Oid, PushOptions, RemoteCallbacks,
-  Repository, ResetType, Signature,
+  Cred, Direction, Error, FetchOptions, ObjectType, Oid, PushOptions, RemoteCallbacks, Repository,
+  ResetType, Signature,
 };
 
 const FORK_REMOTE: &str = "origin";
 const UPSTREAM_REMOTE: &str = "upstream";
-pub const UPSTREAM_BRANCH: &str = "master";
-const SIGNATURE_AUTHOR: &str = "Openshift OTA Bot";
-const SIGNATURE_EMAIL: &str = "vrutkovs@redhat.com";
+
+/// Name and email used in commit signatures and trailers
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+  pub name: String,
+  pub email: String,
+}
+
+impl std::fmt::Display for Identity {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "{} <{}>", self.name, self.email)
+  }
+}
+
+/// Signs commits with an external program
+#[derive(Debug, Clone)]
+pub enum Signer {
+  /// Sign with a GPG key ID
+  Gpg { program: String, key: String },
+  /// Sign with an SSH private key file
+  Ssh { program: String, key_path: PathBuf },
+}
+
+impl Signer {
+  /// Check the key can be used, so misconfigured signing fails before anything is pushed
+  pub fn check(&self) -> Result<(), Error> {
+    self.sign("graph-breaker signing check\n").map(|_| ())
+  }
+
+  /// Return armored detached signature of `content`
+  pub fn sign(&self, content: &str) -> Result<String, Error> {
+    let mut cmd = match self {
+      Signer::Gpg { program, key } => {
+        let mut cmd = Command::new(program);
+        cmd.args(&["--batch", "--detach-sign", "--armor", "--local-user", key]);
+        cmd
+      }
+      Signer::Ssh { program, key_path } => {
+        let mut cmd = Command::new(program);
+        cmd.args(&["-Y", "sign", "-n", "git", "-f"]).arg(key_path);
+        cmd
+      }
+    };
+    let mut child = cmd
+      .stdin(Stdio::piped())
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped())
+      .spawn()
+      .map_err(|e| Error::from_str(&format!("failed to run signing program: {}", e)))?;
+    child
+      .stdin
+      .take()
+      .ok_or_else(|| Error::from_str("failed to open signing program stdin"))?
+      .write_all(content.as_bytes())
+      .map_err(|e| Error::from_str(&format!("failed to pass commit to signing program: {}", e)))?;
+    let output = child
+      .wait_with_output()
+      .map_err(|e| Error::from_str(&format!("signing program failed: {}", e)))?;
+    if !output.status.success() {
+      return Err(Error::from_str(&format!(
+        "signing program failed with {}: {}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr).trim()
+      )));
+    }
+    let signature = String::from_utf8(output.stdout)
+      .map_err(|_| Error::from_str("signature is not valid UTF-8"))?;
+    if signature.trim().is_empty() {
+      return Err(Error::from_str("signing program returned empty signature"));
+    }
+    Ok(signature)
+  }
+}
+
+/// Build commit message, appending trailers as the last paragraph
+pub fn commit_message(title: &str, body: &str, trailers: &[String]) -> String {
+  let message = format!("{}\n{}", title, body);
+  if trailers.is_empty() {
+    return message;
+  }
+  format!("{}\n\n{}", message.trim_end(), trailers.join("\n"))
+}
+
+/// URL used to clone and push to the fork
+pub fn fork_url(org: &str, repo: &str) -> String {
+  format!("git@github.com:{}/{}.git", org, repo)
+}
+
+/// URL used to fetch from upstream
+pub fn upstream_url(org: &str, repo: &str) -> String {
+  format!("https://github.com/{}/{}.git", org, repo)
+}
+
+/// Check the remote can be reached with configured credentials
+#[instrument]
+pub fn check_remote(url: &str) -> Result<(), Error> {
+  debug!("check_remote: {}", url);
+  // Anonymous remotes need a repository, an empty bare one is enough to connect
+  let dir = tempfile::tempdir().map_err(|e| Error::from_str(&e.to_string()))?;
+  let repo = Repository::init_bare(dir.path())?;
+  let mut remote = repo.remote_anonymous(url)?;
+  let _connection = remote.connect_auth(Direction::Fetch, Some(get_ssh_auth_callbacks()), None)?;
+  Ok(())
+}
 
 pub struct GitRepo {
   repo: Repository,
 }
 
 impl GitRepo {
+  #[instrument]
   pub fn new(org: &str, repo: &str, path: &PathBuf) -> Result<Self, Error> {
     debug!("new: cloning {}/{} to {}", org, repo, path.display());
     // Authentication
@@ -27,22 +132,28 @@ impl GitRepo {
     fetch_options.remote_callbacks(get_ssh_auth_callbacks());
     builder.fetch_options(fetch_options);
 
-    let url = format!("git@github.com:{}/{}.git", org, repo);
+    let url = fork_url(org, repo);
     let repo = builder.clone(&url, &path)?;
     debug!("new: done");
     Ok(GitRepo { repo })
   }
 
-  pub fn fetch_from_upstream(&mut self, org_name: &str, repo_name: &str) -> Result<(), Error> {
-    let url = format!("https://github.com/{}/{}.git", org_name, repo_name);
-    debug!("fetch_from_upstream: {}", url);
+  #[instrument(skip(self))]
+  pub fn fetch_from_upstream(
+    &mut self,
+    org_name: &str,
+    repo_name: &str,
+    base_branch: &str,
+  ) -> Result<(), Error> {
+    let url = upstream_url(org_name, repo_name);
+    debug!("fetch_from_upstream: {} {}", url, base_branch);
     let mut fetch_options = FetchOptions::new();
     fetch_options.remote_callbacks(get_ssh_auth_callbacks());
 
     let mut remote = self.repo.remote(UPSTREAM_REMOTE, &url)?;
-    remote.fetch(&[UPSTREAM_BRANCH], Some(&mut fetch_options), None)?;
+    remote.fetch(&[base_branch], Some(&mut fetch_options), None)?;
 
-    let remote_refspec = format!("{}/{}", UPSTREAM_REMOTE, UPSTREAM_BRANCH);
+    let remote_refspec = format!("{}/{}", UPSTREAM_REMOTE, base_branch);
     debug!("fetch_from_upstream: refspec {}", remote_refspec);
     let fetch_head = self.repo.revparse_single(&remote_refspec)?;
     debug!("fetch_from_upstream: fetch_head {}", fetch_head.id());
@@ -53,6 +164,7 @@ impl GitRepo {
       .reset(&fetch_head, ResetType::Hard, Some(cb.force()))
   }
 
+  #[instrument(skip(self))]
   pub fn switch_to(&mut self, branch: &str) -> Result<(), Error> {
     let commit = self.repo.head()?.peel_to_commit()?;
     self.repo.branch(&branch, &commit, true)?;
@@ -62,34 +174,81 @@ impl GitRepo {
     self.repo.set_head(&refname)
   }
 
-  pub fn commit(&mut self, branch: &str, message: String) -> Result<Oid, Error> {
-    // Stage all files
+  /// Stage updated and removed paths, relative to the repo root.
+  /// Returns the new tree or `None` if it matches HEAD, i.e. there is nothing to commit.
+  #[instrument(skip(self))]
+  pub fn stage(&mut self, updated: &[PathBuf], removed: &[PathBuf]) -> Result<Option<Oid>, Error> {
     let mut index = self.repo.index()?;
-    index.add_all(["*"].iter(), IndexAddOption::DEFAULT, None)?;
-    // No files included - skip
-    if index.len() == 0 {
-      return Err(git2::Error::from_str("empty commit detected"));
+    for path in updated {
+      index.add_path(path)?;
+    }
+    for path in removed {
+      index.remove_path(path)?;
     }
+    index.write()?;
     let oid = index.write_tree()?;
+    let head_tree = self.repo.head()?.peel_to_tree()?;
+    if head_tree.id() == oid {
+      debug!("stage: tree matches HEAD, nothing to commit");
+      return Ok(None);
+    }
+    Ok(Some(oid))
+  }
+
+  #[instrument(skip(self, message))]
+  pub fn commit(
+    &mut self,
+    branch: &str,
+    tree: Oid,
+    message: String,
+    author: &Identity,
+    committer: &Identity,
+    signer: Option<&Signer>,
+  ) -> Result<Oid, Error> {
     // Prepare commit metadata
-    let signature = Signature::now(SIGNATURE_AUTHOR, SIGNATURE_EMAIL)?;
+    let author_signature = Signature::now(&author.name, &author.email)?;
+    let committer_signature = Signature::now(&committer.name, &committer.email)?;
     let obj = self.repo.head()?.resolve()?.peel(ObjectType::Commit)?;
     let parent_commit = obj
       .into_commit()
       .map_err(|_| Error::from_str("Couldn't find commit"))?;
-    let tree = self.repo.find_tree(oid)?;
+    let tree = self.repo.find_tree(tree)?;
     // Create a new HEAD commit
     let refname = format!("refs/heads/{}", &branch);
-    self.repo.commit(
-      Some(&refname),
-      &signature,
-      &signature,
+    let signer = match signer {
+      Some(signer) => signer,
+      None => {
+        return self.repo.commit(
+          Some(&refname),
+          &author_signature,
+          &committer_signature,
+          &message,
+          &tree,
+          &[&parent_commit],
+        )
+      }
+    };
+    let buf = self.repo.commit_create_buffer(
+      &author_signature,
+      &committer_signature,
       &message,
       &tree,
       &[&parent_commit],
-    )
+    )?;
+    let content = buf
+      .as_str()
+      .ok_or_else(|| Error::from_str("commit content is not valid UTF-8"))?;
+    let signature = signer.sign(content)?;
+    let oid = self
+      .repo
+      .commit_signed(content, &signature, Some("gpgsig"))?;
+    self
+      .repo
+      .reference(&refname, oid, true, "graph-breaker: signed commit")?;
+    Ok(oid)
   }
 
+  #[instrument(skip(self))]
   pub fn push_to_remote(&mut self, branch: &str) -> Result<(), Error> {
     let mut push_options = PushOptions::new();
     push_options.remote_callbacks(get_ssh_auth_callbacks());
@@ -120,3 +279,149 @@ fn get_ssh_auth_callbacks<'cb>() -> RemoteCallbacks<'cb> {
   });
   callbacks
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+  use std::os::unix::fs::PermissionsExt;
+  use std::path::Path;
+  use tempfile::tempdir;
+
+  /// Repository with a single commit containing blocked edges for 4.6.1 and 4.6.2
+  fn init_repo(path: &Path) -> GitRepo {
+    let repo = Repository::init(path).unwrap();
+    fs::create_dir(path.join("blocked-edges")).unwrap();
+    for version in &["4.6.1", "4.6.2"] {
+      fs::write(
+        path.join("blocked-edges").join(format!("{}.yaml", version)),
+        format!("to: {}\nfrom: .*\n", version),
+      )
+      .unwrap();
+    }
+    {
+      let mut index = repo.index().unwrap();
+      index
+        .add_path(Path::new("blocked-edges/4.6.1.yaml"))
+        .unwrap();
+      index
+        .add_path(Path::new("blocked-edges/4.6.2.yaml"))
+        .unwrap();
+      index.write().unwrap();
+      let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+      let signature = Signature::now("Test", "test@example.com").unwrap();
+      repo
+        .commit(Some("HEAD"), &signature, &signature, "Initial", &tree, &[])
+        .unwrap();
+    }
+    GitRepo { repo }
+  }
+
+  /// Executable shell script acting as signing program
+  fn stub_program(dir: &Path, script: &str) -> String {
+    let path = dir.join("sign");
+    fs::write(&path, format!("#!/bin/sh\ncat > /dev/null\n{}", script)).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path.display().to_string()
+  }
+
+  fn identity() -> Identity {
+    Identity {
+      name: "Openshift OTA Bot".to_string(),
+      email: "ota-bot@example.com".to_string(),
+    }
+  }
+
+  #[test]
+  fn commit_signed() {
+    let tmpdir = tempdir().unwrap();
+    let bindir = tempdir().unwrap();
+    let mut repo = init_repo(tmpdir.path());
+    let signer = Signer::Gpg {
+      program: stub_program(
+        bindir.path(),
+        "echo '-----BEGIN PGP SIGNATURE-----'\necho\necho c3R1Yg==\necho '-----END PGP SIGNATURE-----'\n",
+      ),
+      key: "0123456789ABCDEF".to_string(),
+    };
+    signer.check().unwrap();
+
+    let tree = repo.repo.head().unwrap().peel_to_tree().unwrap().id();
+    let oid = repo
+      .commit(
+        "signed",
+        tree,
+        "Block edge 4.6.3\n".to_string(),
+        &identity(),
+        &identity(),
+        Some(&signer),
+      )
+      .unwrap();
+    let (signature, signed_data) = repo.repo.extract_signature(&oid, Some("gpgsig")).unwrap();
+    assert!(signature
+      .as_str()
+      .unwrap()
+      .starts_with("-----BEGIN PGP SIGNATURE-----\n"));
+    assert!(signed_data
+      .as_str()
+      .unwrap()
+      .ends_with("\nBlock edge 4.6.3\n"));
+    let branch = repo.repo.refname_to_id("refs/heads/signed").unwrap();
+    assert_eq!(branch, oid);
+  }
+
+  #[test]
+  fn signing_program_failure() {
+    let bindir = tempdir().unwrap();
+    let signer = Signer::Ssh {
+      program: stub_program(bindir.path(), "echo 'no such key' >&2\nexit 2\n"),
+      key_path: bindir.path().join("id_ed25519"),
+    };
+    let err = signer.check().unwrap_err();
+    assert!(
+      err.message().starts_with("signing program failed with"),
+      "{}",
+      err
+    );
+    assert!(err.message().ends_with(": no such key"), "{}", err);
+
+    let signer = Signer::Gpg {
+      program: bindir.path().join("missing").display().to_string(),
+      key: "0123456789ABCDEF".to_string(),
+    };
+    let err = signer.check().unwrap_err();
+    assert!(err.message().starts_with("failed to run signing program"));
+  }
+
+  #[test]
+  fn stage_changes() {
+    let tmpdir = tempdir().unwrap();
+    let mut repo = init_repo(tmpdir.path());
+    let updated = PathBuf::from("blocked-edges/4.6.1.yaml");
+    let removed = PathBuf::from("blocked-edges/4.6.2.yaml");
+
+    // Re-adding an unchanged file leaves the tree as in HEAD
+    assert_eq!(repo.stage(std::slice::from_ref(&updated), &[]).unwrap(), None);
+
+    fs::write(
+      tmpdir.path().join(&updated),
+      "to: 4.6.1\nfrom: 4\\.5\\..*\n",
+    )
+    .unwrap();
+    fs::remove_file(tmpdir.path().join(&removed)).unwrap();
+    let oid = repo
+      .stage(std::slice::from_ref(&updated), std::slice::from_ref(&removed))
+      .unwrap()
+      .unwrap();
+    let tree = repo.repo.find_tree(oid).unwrap();
+    let blob = tree
+      .get_path(&updated)
+      .unwrap()
+      .to_object(&repo.repo)
+      .unwrap()
+      .peel_to_blob()
+      .unwrap();
+    assert_eq!(blob.content(), b"to: 4.6.1\nfrom: 4\\.5\\..*\n");
+    assert!(tree.get_path(&removed).is_err());
+  }
+}
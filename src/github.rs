@@ -1,34 +1,160 @@
-use crate::{action, git_repo};
+use crate::action;
 
+use anyhow::Error;
 use futures::prelude::*;
 use log::debug;
+use reqwest::header::{ACCEPT, AUTHORIZATION, USER_AGENT};
+use reqwest::StatusCode;
+use tracing::instrument;
 
 use hubcaps::comments::CommentOptions;
 use hubcaps::pulls::PullOptions;
 use hubcaps::repositories::Repository;
 use hubcaps::{Credentials, Github};
 
+const GITHUB_API: &str = "https://api.github.com";
+const GITHUB_USER_AGENT: &str = "graph-breaker/0.1.0";
+const SCOPES_HEADER: &str = "x-oauth-scopes";
+const JSON_MEDIA_TYPE: &str = "application/vnd.github.v3+json";
+const RAW_MEDIA_TYPE: &str = "application/vnd.github.v3.raw";
+
+/// Repository permissions granted to the token
+#[derive(Debug, Default, Deserialize)]
+pub struct RepoPermissions {
+  #[serde(default)]
+  pub admin: bool,
+  #[serde(default)]
+  pub push: bool,
+  #[serde(default)]
+  pub pull: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ContentEntry {
+  name: String,
+  #[serde(rename = "type")]
+  kind: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RepoInfo {
+  #[serde(default)]
+  permissions: RepoPermissions,
+}
+
+async fn api_send(token: &str, path: &str, accept: &str) -> Result<reqwest::Response, Error> {
+  let resp = reqwest::Client::new()
+    .get(&format!("{}{}", GITHUB_API, path))
+    .header(AUTHORIZATION, format!("token {}", token))
+    .header(USER_AGENT, GITHUB_USER_AGENT)
+    .header(ACCEPT, accept)
+    .send()
+    .await?;
+  Ok(resp)
+}
+
+#[instrument(skip(token))]
+async fn api_get(token: &str, path: &str) -> Result<reqwest::Response, Error> {
+  let resp = api_send(token, path, JSON_MEDIA_TYPE).await?;
+  if !resp.status().is_success() {
+    bail!("GET {} returned {}", path, resp.status());
+  }
+  Ok(resp)
+}
+
+/// Verify the token and return OAuth scopes granted to it.
+/// Tokens which don't use OAuth scopes (e.g. app tokens) return no scopes.
+pub async fn token_scopes(token: &str) -> Result<Vec<String>, Error> {
+  let resp = api_get(token, "/user").await?;
+  let scopes = match resp.headers().get(SCOPES_HEADER) {
+    Some(value) => value
+      .to_str()?
+      .split(',')
+      .map(|s| s.trim().to_string())
+      .filter(|s| !s.is_empty())
+      .collect(),
+    None => vec![],
+  };
+  Ok(scopes)
+}
+
+/// Return permissions the token has on the repository
+pub async fn repo_permissions(
+  token: &str,
+  org_name: &str,
+  repo_name: &str,
+) -> Result<RepoPermissions, Error> {
+  let resp = api_get(token, &format!("/repos/{}/{}", org_name, repo_name)).await?;
+  let info: RepoInfo = resp.json().await?;
+  Ok(info.permissions)
+}
+
+/// Return contents of the file on the branch, `None` if it doesn't exist
+#[instrument(skip(token))]
+pub async fn file_contents(
+  token: &str,
+  org_name: &str,
+  repo_name: &str,
+  path: &str,
+  branch: &str,
+) -> Result<Option<String>, Error> {
+  let api_path = format!(
+    "/repos/{}/{}/contents/{}?ref={}",
+    org_name, repo_name, path, branch
+  );
+  let resp = api_send(token, &api_path, RAW_MEDIA_TYPE).await?;
+  match resp.status() {
+    StatusCode::NOT_FOUND => Ok(None),
+    status if status.is_success() => Ok(Some(resp.text().await?)),
+    status => bail!("GET {} returned {}", api_path, status),
+  }
+}
+
+/// Return names of files in the directory on the branch
+pub async fn list_directory(
+  token: &str,
+  org_name: &str,
+  repo_name: &str,
+  path: &str,
+  branch: &str,
+) -> Result<Vec<String>, Error> {
+  let api_path = format!(
+    "/repos/{}/{}/contents/{}?ref={}",
+    org_name, repo_name, path, branch
+  );
+  let entries: Vec<ContentEntry> = api_get(token, &api_path).await?.json().await?;
+  Ok(
+    entries
+      .into_iter()
+      .filter(|entry| entry.kind == "file")
+      .map(|entry| entry.name)
+      .collect(),
+  )
+}
+
 pub struct GithubRepo {
   repo: Repository,
 }
 
 impl GithubRepo {
   pub fn new(token: String, org_name: &str, repo_name: &str) -> Self {
-    let client = Github::new("graph-breaker/0.1.0", Credentials::Token(token)).unwrap();
+    let client = Github::new(GITHUB_USER_AGENT, Credentials::Token(token)).unwrap();
     let repo = client.repo(org_name, repo_name);
     GithubRepo { repo: repo }
   }
 
+  #[instrument(skip(self, action))]
   pub async fn create_pr(
     &mut self,
     fork_org: &str,
     fork_branch: &str,
+    base_branch: &str,
     action: action::Action,
   ) -> Result<String, hubcaps::Error> {
     let (title, body) = action.to_pr_tuple();
 
     let pr = PullOptions {
-      base: git_repo::UPSTREAM_BRANCH.to_string(),
+      base: base_branch.to_string(),
       head: format!("{}:{}", fork_org, fork_branch),
       title: title.to_string(),
       body: Some(body.to_string()),
@@ -37,7 +163,13 @@ impl GithubRepo {
     Ok(pull.html_url.clone())
   }
 
-  pub async fn has_open_pr_for(&mut self, version: &str) -> Result<Option<u64>, hubcaps::Error> {
+  /// Find open PR for the version against base branch
+  #[instrument(skip(self))]
+  pub async fn has_open_pr_for(
+    &mut self,
+    version: &str,
+    base_branch: &str,
+  ) -> Result<Option<u64>, hubcaps::Error> {
     debug!("Looking for similar pull requests");
     let mut pr_stream = self.repo.pulls().iter(&Default::default());
     while let Some(item) = pr_stream.next().await {
@@ -48,7 +180,7 @@ impl GithubRepo {
       let pr = item.unwrap();
       debug!("Checking #{}: {}", pr.number, pr.title);
       // Check base branch
-      if pr.base.commit_ref != git_repo::UPSTREAM_BRANCH {
+      if pr.base.commit_ref != base_branch {
         debug!("Wrong commit_ref: {}", pr.base.commit_ref);
         continue;
       }
@@ -63,6 +195,21 @@ impl GithubRepo {
     Ok(None)
   }
 
+  /// Count open pull requests from branches of the fork organization
+  #[instrument(skip(self))]
+  pub async fn count_open_prs(&mut self, fork_org: &str) -> Result<i64, hubcaps::Error> {
+    let fork_label_prefix = format!("{}:", fork_org);
+    let mut open_prs = 0;
+    let mut pr_stream = self.repo.pulls().iter(&Default::default());
+    while let Some(item) = pr_stream.next().await {
+      if item?.head.label.starts_with(&fork_label_prefix) {
+        open_prs += 1;
+      }
+    }
+    Ok(open_prs)
+  }
+
+  #[instrument(skip(self, comment))]
   pub async fn comment_in_pr(&mut self, id: u64, comment: &str) -> Result<String, hubcaps::Error> {
     let pr = self.repo.pulls().get(id);
     let comment_opts = CommentOptions {
@@ -72,6 +219,7 @@ impl GithubRepo {
     Ok(pr.get().await?.html_url.clone())
   }
 
+  #[instrument(skip(self))]
   pub async fn get_action_from_pr_title(&mut self, id: u64) -> Result<String, hubcaps::Error> {
     let pr = self.repo.pulls().get(id);
     Ok(
@@ -85,6 +233,7 @@ impl GithubRepo {
     )
   }
 
+  #[instrument(skip(self))]
   pub async fn close_pr(&mut self, id: u64) -> Result<String, hubcaps::Error> {
     let pr = self.repo.pulls().get(id);
     let _ = pr.close().await;
@@ -0,0 +1,491 @@
+//! Update graph built from graph-data, used to preview the impact of actions on upgrade paths
+
+use crate::graph_schema;
+
+use anyhow::{Context, Error};
+use log::debug;
+use regex::Regex;
+use semver::Version;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// Number of entries listed per report section
+const MAX_REPORT_ENTRIES: usize = 20;
+
+/// Availability of an edge left after applying blocked edges
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EdgeState {
+  /// Recommended to all clusters
+  Recommended,
+  /// Recommended only to clusters not matching a risk
+  Conditional,
+}
+
+/// Cincinnati graph JSON, only versions and edges are used
+#[derive(Debug, Deserialize)]
+struct Snapshot {
+  nodes: Vec<SnapshotNode>,
+  #[serde(default)]
+  edges: Vec<(usize, usize)>,
+  #[serde(default, rename = "conditionalEdges")]
+  conditional_edges: Vec<SnapshotConditionalEdges>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SnapshotNode {
+  version: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SnapshotConditionalEdges {
+  edges: Vec<SnapshotEdge>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SnapshotEdge {
+  from: String,
+  to: String,
+}
+
+/// Possible updates between releases
+pub type Candidates = BTreeSet<(Version, Version)>;
+
+/// Read possible updates from a Cincinnati graph JSON snapshot, including conditional edges
+pub fn read_snapshot(path: &Path) -> Result<Candidates, Error> {
+  let contents = fs::read_to_string(path)?;
+  let snapshot: Snapshot = serde_json::from_str(&contents)
+    .with_context(|| format!("{} is not a Cincinnati graph", path.display()))?;
+  let versions: Vec<Option<Version>> = snapshot
+    .nodes
+    .iter()
+    .map(|node| Version::parse(&node.version).ok())
+    .collect();
+  let mut candidates = BTreeSet::new();
+  for (from, to) in &snapshot.edges {
+    match (versions.get(*from), versions.get(*to)) {
+      (Some(Some(from)), Some(Some(to))) => {
+        candidates.insert((from.clone(), to.clone()));
+      }
+      _ => debug!("Skipping snapshot edge {} -> {}", from, to),
+    }
+  }
+  for edge in snapshot
+    .conditional_edges
+    .iter()
+    .flat_map(|c| c.edges.iter())
+  {
+    match (Version::parse(&edge.from), Version::parse(&edge.to)) {
+      (Ok(from), Ok(to)) => {
+        candidates.insert((from, to));
+      }
+      _ => debug!("Skipping snapshot edge {} -> {}", edge.from, edge.to),
+    }
+  }
+  Ok(candidates)
+}
+
+/// Update graph of a graph-data repo
+#[derive(Debug, Default)]
+pub struct Graph {
+  /// Releases of each channel, sorted by version
+  channels: BTreeMap<String, Vec<Version>>,
+  /// Edges left after applying blocked edges
+  edges: BTreeMap<(Version, Version), EdgeState>,
+}
+
+impl Graph {
+  /// Build the graph from channels and blocked edges of the repo at `path`.
+  /// Releases may update to newer releases in the same channel, limited to `candidates` if set.
+  pub fn load(path: &Path, candidates: Option<&Candidates>) -> Result<Self, Error> {
+    let channels = graph_schema::channels(path)?;
+    let mut blocked: BTreeMap<Version, Vec<(Regex, bool)>> = BTreeMap::new();
+    for edge in graph_schema::blocked_edges(path)? {
+      let (to, from) = match (Version::parse(&edge.to), edge.from_regex()) {
+        (Ok(to), Ok(from)) => (to, from),
+        _ => {
+          debug!("Skipping blocked edge {}", edge);
+          continue;
+        }
+      };
+      blocked
+        .entry(to)
+        .or_default()
+        .push((from, edge.risk().is_some()));
+    }
+
+    let mut edges = BTreeMap::new();
+    for releases in channels.values() {
+      for (i, from) in releases.iter().enumerate() {
+        let from_version = from.to_string();
+        for to in &releases[i + 1..] {
+          let pair = (from.clone(), to.clone());
+          if edges.contains_key(&pair) || candidates.map_or(false, |c| !c.contains(&pair)) {
+            continue;
+          }
+          let mut state = Some(EdgeState::Recommended);
+          for (regex, conditional) in blocked.get(to).into_iter().flatten() {
+            if regex.is_match(&from_version) {
+              if !conditional {
+                state = None;
+                break;
+              }
+              state = Some(EdgeState::Conditional);
+            }
+          }
+          if let Some(state) = state {
+            edges.insert(pair, state);
+          }
+        }
+      }
+    }
+    Ok(Graph { channels, edges })
+  }
+
+  /// Return releases of each channel, leaving out `withdrawn` ones
+  fn listed<'a>(
+    &'a self,
+    withdrawn: &'a BTreeSet<Version>,
+  ) -> impl Iterator<Item = (&'a String, Vec<&'a Version>)> {
+    self.channels.iter().map(move |(channel, releases)| {
+      let releases = releases.iter().filter(|r| !withdrawn.contains(r)).collect();
+      (channel, releases)
+    })
+  }
+
+  /// Return releases, other than the newest one of a channel,
+  /// without an update to a newer release in the channel. `withdrawn` releases are ignored.
+  pub fn stranded(&self, withdrawn: &BTreeSet<Version>) -> BTreeSet<(String, Version)> {
+    let mut stranded = BTreeSet::new();
+    for (channel, releases) in self.listed(withdrawn) {
+      for (i, from) in releases.iter().enumerate().rev().skip(1) {
+        let has_update = releases[i + 1..]
+          .iter()
+          .any(|to| self.edges.contains_key(&((*from).clone(), (*to).clone())));
+        if !has_update {
+          stranded.insert((channel.clone(), (*from).clone()));
+        }
+      }
+    }
+    stranded
+  }
+
+  /// Return newest releases of channels no other release in the channel can update to.
+  /// `withdrawn` releases are ignored.
+  pub fn unreachable_heads(&self, withdrawn: &BTreeSet<Version>) -> BTreeSet<(String, Version)> {
+    let mut unreachable = BTreeSet::new();
+    for (channel, releases) in self.listed(withdrawn) {
+      if let Some((head, others)) = releases.split_last() {
+        let reachable = others
+          .iter()
+          .any(|from| self.edges.contains_key(&((*from).clone(), (*head).clone())));
+        if !others.is_empty() && !reachable {
+          unreachable.insert((channel.clone(), (*head).clone()));
+        }
+      }
+    }
+    unreachable
+  }
+}
+
+/// Return releases in `after` missing in `before`
+fn newly_listed(
+  before: BTreeSet<(String, Version)>,
+  after: BTreeSet<(String, Version)>,
+) -> Vec<StrandedRelease> {
+  after
+    .difference(&before)
+    .map(|(channel, version)| StrandedRelease {
+      version: version.to_string(),
+      channel: channel.clone(),
+    })
+    .collect()
+}
+
+/// Update between two releases
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Edge {
+  pub from: String,
+  pub to: String,
+}
+
+impl Edge {
+  fn new((from, to): &(Version, Version)) -> Self {
+    Edge {
+      from: from.to_string(),
+      to: to.to_string(),
+    }
+  }
+}
+
+impl fmt::Display for Edge {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{} -> {}", self.from, self.to)
+  }
+}
+
+/// Release without an update path in a channel
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StrandedRelease {
+  pub version: String,
+  pub channel: String,
+}
+
+impl fmt::Display for StrandedRelease {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{} in {}", self.version, self.channel)
+  }
+}
+
+/// Difference between the update graphs before and after an action
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Impact {
+  /// Edges no longer recommended to any cluster
+  pub removed: Vec<Edge>,
+  /// Edges recommended to all clusters again, or added with a release
+  pub restored: Vec<Edge>,
+  /// Edges now recommended only to clusters not matching a risk
+  pub conditional: Vec<Edge>,
+  /// Releases left without an update to a newer release in their channel
+  pub stranded: Vec<StrandedRelease>,
+  /// Channel heads no other release in the channel can update to anymore
+  pub unreachable_heads: Vec<StrandedRelease>,
+}
+
+impl Impact {
+  /// Compare update graphs. Releases in `blocked` which no release can update to after the action
+  /// are withdrawn on purpose, so they aren't counted as unreachable heads or as update targets.
+  pub fn between(before: &Graph, after: &Graph, blocked: &BTreeSet<Version>) -> Self {
+    let mut impact = Impact::default();
+    for (pair, state) in &before.edges {
+      match (state, after.edges.get(pair)) {
+        (_, None) => impact.removed.push(Edge::new(pair)),
+        (EdgeState::Recommended, Some(EdgeState::Conditional)) => {
+          impact.conditional.push(Edge::new(pair))
+        }
+        _ => {}
+      }
+    }
+    for (pair, state) in &after.edges {
+      match (before.edges.get(pair), state) {
+        (None, _) | (Some(EdgeState::Conditional), EdgeState::Recommended) => {
+          impact.restored.push(Edge::new(pair))
+        }
+        _ => {}
+      }
+    }
+    let withdrawn: BTreeSet<Version> = blocked
+      .iter()
+      .filter(|version| !after.edges.keys().any(|(_, to)| to == *version))
+      .cloned()
+      .collect();
+    impact.stranded = newly_listed(before.stranded(&withdrawn), after.stranded(&withdrawn));
+    impact.unreachable_heads = newly_listed(
+      before.unreachable_heads(&withdrawn),
+      after.unreachable_heads(&withdrawn),
+    );
+    impact
+  }
+
+  /// The action leaves releases without an update path
+  pub fn strands_releases(&self) -> bool {
+    !self.stranded.is_empty() || !self.unreachable_heads.is_empty()
+  }
+
+  /// The action doesn't change the update graph
+  pub fn is_empty(&self) -> bool {
+    self.removed.is_empty()
+      && self.restored.is_empty()
+      && self.conditional.is_empty()
+      && !self.strands_releases()
+  }
+}
+
+/// Write a markdown list, long lists are truncated
+fn write_section<T: fmt::Display>(
+  f: &mut fmt::Formatter,
+  title: &str,
+  entries: &[T],
+) -> fmt::Result {
+  if entries.is_empty() {
+    return Ok(());
+  }
+  write!(f, "\n\n{} ({}):", title, entries.len())?;
+  for entry in entries.iter().take(MAX_REPORT_ENTRIES) {
+    write!(f, "\n- {}", entry)?;
+  }
+  if entries.len() > MAX_REPORT_ENTRIES {
+    write!(f, "\n- ... and {} more", entries.len() - MAX_REPORT_ENTRIES)?;
+  }
+  Ok(())
+}
+
+impl fmt::Display for Impact {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if self.is_empty() {
+      return write!(f, "Update graph impact: none");
+    }
+    write!(f, "Update graph impact:")?;
+    write_section(f, "Removed edges", &self.removed)?;
+    write_section(f, "Restored edges", &self.restored)?;
+    write_section(f, "Edges made conditional", &self.conditional)?;
+    write_section(f, "Releases left without an update path", &self.stranded)?;
+    write_section(f, "Channel heads left unreachable", &self.unreachable_heads)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::graph_schema::{BlockedEdge, ALL_VERSIONS_REGEXP};
+  use tempfile::tempdir;
+
+  fn repo() -> tempfile::TempDir {
+    let tmpdir = tempdir().unwrap();
+    fs::create_dir(tmpdir.path().join("channels")).unwrap();
+    fs::create_dir(tmpdir.path().join(graph_schema::BLOCKED_DIR)).unwrap();
+    fs::write(
+      tmpdir.path().join("channels").join("fast-4.6.yaml"),
+      "name: fast-4.6\nversions:\n- 4.5.16\n- 4.6.1\n- 4.6.3\n",
+    )
+    .unwrap();
+    tmpdir
+  }
+
+  fn edges(edges: &[Edge]) -> Vec<String> {
+    edges.iter().map(|e| e.to_string()).collect()
+  }
+
+  fn versions(versions: &[&str]) -> BTreeSet<Version> {
+    versions
+      .iter()
+      .map(|v| Version::parse(v).unwrap())
+      .collect()
+  }
+
+  #[test]
+  fn block_edge_impact() {
+    let tmpdir = repo();
+    let before = Graph::load(tmpdir.path(), None).unwrap();
+    graph_schema::block_edge(
+      tmpdir.path(),
+      &BlockedEdge::new("4.6.3", ALL_VERSIONS_REGEXP, None),
+    )
+    .unwrap();
+    let after = Graph::load(tmpdir.path(), None).unwrap();
+
+    // Blocking the channel head withdraws it, 4.6.1 becomes the newest release
+    let impact = Impact::between(&before, &after, &versions(&["4.6.3"]));
+    assert_eq!(
+      edges(&impact.removed),
+      vec!["4.5.16 -> 4.6.3", "4.6.1 -> 4.6.3"]
+    );
+    assert!(impact.restored.is_empty());
+    assert!(!impact.strands_releases());
+
+    // Removing the edges without blocking the head leaves it unreachable
+    let impact = Impact::between(&before, &after, &BTreeSet::new());
+    assert_eq!(
+      impact.stranded,
+      vec![StrandedRelease {
+        version: "4.6.1".to_string(),
+        channel: "fast-4.6".to_string(),
+      }]
+    );
+    assert_eq!(
+      impact.unreachable_heads,
+      vec![StrandedRelease {
+        version: "4.6.3".to_string(),
+        channel: "fast-4.6".to_string(),
+      }]
+    );
+    assert!(impact.strands_releases());
+
+    let impact = Impact::between(&after, &before, &BTreeSet::new());
+    assert_eq!(
+      edges(&impact.restored),
+      vec!["4.5.16 -> 4.6.3", "4.6.1 -> 4.6.3"]
+    );
+    assert!(impact.stranded.is_empty());
+    assert!(Impact::between(&after, &after, &BTreeSet::new()).is_empty());
+  }
+
+  #[test]
+  fn partial_block_strands_release() {
+    let tmpdir = repo();
+    let before = Graph::load(tmpdir.path(), None).unwrap();
+    graph_schema::block_edge(
+      tmpdir.path(),
+      &BlockedEdge::new("4.6.3", "4\\.6\\..*", None),
+    )
+    .unwrap();
+    let after = Graph::load(tmpdir.path(), None).unwrap();
+
+    // 4.6.3 is still reachable from 4.5.16, so it isn't withdrawn
+    let impact = Impact::between(&before, &after, &versions(&["4.6.3"]));
+    assert_eq!(edges(&impact.removed), vec!["4.6.1 -> 4.6.3"]);
+    assert_eq!(
+      impact.stranded,
+      vec![StrandedRelease {
+        version: "4.6.1".to_string(),
+        channel: "fast-4.6".to_string(),
+      }]
+    );
+    assert!(impact.unreachable_heads.is_empty());
+  }
+
+  #[test]
+  fn conditional_edge_impact() {
+    let tmpdir = repo();
+    let before = Graph::load(tmpdir.path(), None).unwrap();
+    let risk = graph_schema::Risk {
+      name: "SomeRisk".to_string(),
+      url: "https://bugzilla.redhat.com/show_bug.cgi?id=1".to_string(),
+      message: "Clusters on AWS may fail to update.".to_string(),
+      matching_rules: vec![graph_schema::MatchingRule::Always],
+    };
+    graph_schema::block_edge(
+      tmpdir.path(),
+      &BlockedEdge::new("4.6.3", "4\\.5\\..*", Some(&risk)),
+    )
+    .unwrap();
+    let after = Graph::load(tmpdir.path(), None).unwrap();
+
+    let impact = Impact::between(&before, &after, &versions(&["4.6.3"]));
+    assert!(impact.removed.is_empty());
+    assert_eq!(edges(&impact.conditional), vec!["4.5.16 -> 4.6.3"]);
+    assert!(!impact.strands_releases());
+    assert_eq!(
+      impact.to_string(),
+      "Update graph impact:\n\nEdges made conditional (1):\n- 4.5.16 -> 4.6.3"
+    );
+  }
+
+  #[test]
+  fn snapshot_candidates() {
+    let tmpdir = repo();
+    let snapshot_path = tmpdir.path().join("graph.json");
+    fs::write(
+      &snapshot_path,
+      r#"{
+        "nodes": [{"version": "4.5.16"}, {"version": "4.6.1"}, {"version": "4.6.3"}],
+        "edges": [[0, 1], [1, 2]],
+        "conditionalEdges": [{"edges": [{"from": "4.5.16", "to": "4.6.3"}], "risks": []}]
+      }"#,
+    )
+    .unwrap();
+    let candidates = read_snapshot(&snapshot_path).unwrap();
+    assert_eq!(candidates.len(), 3);
+
+    let graph = Graph::load(tmpdir.path(), Some(&candidates)).unwrap();
+    assert!(graph.stranded(&BTreeSet::new()).is_empty());
+    let mut candidates = candidates;
+    candidates.remove(&(
+      Version::parse("4.6.1").unwrap(),
+      Version::parse("4.6.3").unwrap(),
+    ));
+    let graph = Graph::load(tmpdir.path(), Some(&candidates)).unwrap();
+    assert_eq!(graph.stranded(&BTreeSet::new()).len(), 1);
+  }
+}
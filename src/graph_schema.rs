@@ -1,41 +1,1097 @@
-use anyhow::Error;
+use crate::errors::AppError;
+
+use anyhow::{Context, Error};
+use log::debug;
+use regex::Regex;
+use semver::{Version, VersionReq};
+use serde_yaml::{Mapping, Value};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 use std::fs;
 use std::path::{Path, PathBuf};
 
-const BLOCKED_DIR: &str = "blocked-edges";
-const ALL_VERSIONS_REGEXP: &str = ".*";
+pub const BLOCKED_DIR: &str = "blocked-edges";
+const CHANNELS_DIR: &str = "channels";
+pub const ALL_VERSIONS_REGEXP: &str = ".*";
+/// Keys managed by graph-breaker, other keys in blocked edges files are kept as is
+const EDGE_KEYS: &[&str] = &["to", "from", "url", "name", "message", "matchingRules"];
+
+/// Rule selecting clusters affected by a conditional risk
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum MatchingRule {
+  /// Clusters for which the PromQL query returns 1
+  PromQL { promql: PromQLQuery },
+  /// All clusters
+  Always,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PromQLQuery {
+  pub promql: String,
+}
+
+/// Conditional update risk, clusters not matching the rules keep the edge
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Risk {
+  /// CamelCase risk name, e.g. `AWSOldBootImages`
+  pub name: String,
+  /// Link to the bug or knowledge base article
+  pub url: String,
+  /// Human-readable explanation shown to cluster admins
+  pub message: String,
+  #[serde(rename = "matchingRules")]
+  pub matching_rules: Vec<MatchingRule>,
+}
+
+impl Risk {
+  /// Return validation errors for the risk
+  pub fn validate(&self) -> Vec<String> {
+    let mut errors = vec![];
+    let mut chars = self.name.chars();
+    let valid_name = chars.next().map_or(false, |c| c.is_ascii_uppercase())
+      && chars.all(|c| c.is_ascii_alphanumeric());
+    if !valid_name {
+      errors.push(format!(
+        "risk name {:?} must be CamelCase ASCII letters and digits",
+        self.name
+      ));
+    }
+    match url::Url::parse(&self.url) {
+      Ok(url) if url.scheme() == "https" || url.scheme() == "http" => {}
+      _ => errors.push(format!("risk url {:?} must be an http(s) URL", self.url)),
+    }
+    if self.message.trim().is_empty() {
+      errors.push("risk message must not be empty".to_string());
+    }
+    if self.matching_rules.is_empty() {
+      errors.push("risk must have at least one matching rule".to_string());
+    }
+    for rule in &self.matching_rules {
+      if let MatchingRule::PromQL { promql } = rule {
+        if promql.promql.trim().is_empty() {
+          errors.push("PromQL matching rule must have a query".to_string());
+        }
+      }
+    }
+    errors
+  }
+}
+
+/// Blocked edges file, edges to `to` from versions matching `from` are blocked
+/// for all clusters, or only for clusters matching the risk rules
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockedEdge {
+  pub to: String,
+  pub from: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub url: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub name: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub message: Option<String>,
+  #[serde(rename = "matchingRules", skip_serializing_if = "Option::is_none")]
+  pub matching_rules: Option<Vec<MatchingRule>>,
+}
+
+impl BlockedEdge {
+  /// Block edges to `to` from versions matching `from`, optionally for clusters matching the risk
+  pub fn new(to: &str, from: &str, risk: Option<&Risk>) -> Self {
+    BlockedEdge {
+      to: to.to_string(),
+      from: from.to_string(),
+      url: risk.map(|r| r.url.clone()),
+      name: risk.map(|r| r.name.clone()),
+      message: risk.map(|r| r.message.clone()),
+      matching_rules: risk.map(|r| r.matching_rules.clone()),
+    }
+  }
+
+  /// Return validation errors for the edge
+  pub fn validate(&self) -> Vec<String> {
+    let mut errors = vec![];
+    if !is_safe_file_stem(&self.to) {
+      errors.push(format!(
+        "blocked edge version {:?} can't be used as a file name",
+        self.to
+      ));
+    }
+    errors.extend(validate_from(&self.from));
+    let risk_fields = [
+      self.url.is_some(),
+      self.name.is_some(),
+      self.message.is_some(),
+      self.matching_rules.is_some(),
+    ];
+    match self.risk() {
+      Some(risk) => errors.extend(risk.validate()),
+      None if risk_fields.iter().any(|set| *set) => errors
+        .push("conditional edge requires all of url, name, message and matchingRules".to_string()),
+      None => {}
+    }
+    errors
+  }
+
+  /// Compile `from` into a regexp matching whole source versions
+  pub fn from_regex(&self) -> Result<Regex, regex::Error> {
+    full_match_regex(&self.from)
+  }
+
+  /// Return conditional risk if the edge declares one
+  pub fn risk(&self) -> Option<Risk> {
+    match (&self.url, &self.name, &self.message, &self.matching_rules) {
+      (Some(url), Some(name), Some(message), Some(matching_rules)) => Some(Risk {
+        name: name.clone(),
+        url: url.clone(),
+        message: message.clone(),
+        matching_rules: matching_rules.clone(),
+      }),
+      _ => None,
+    }
+  }
+}
+
+impl fmt::Display for BlockedEdge {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{} from `{}`", self.to, self.from)?;
+    match &self.name {
+      Some(name) => write!(f, " for clusters matching {}", name),
+      None => Ok(()),
+    }
+  }
+}
+
+/// Edges to lift from a blocked edges file, everything by default
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Unblock {
+  /// Only lift this alternative of the `from` regexp
+  pub from: Option<String>,
+  /// Only lift the edge if it declares the risk with this name
+  pub risk_name: Option<String>,
+}
+
+impl Unblock {
+  /// Check the edge is selected by `risk_name`
+  fn applies_to(&self, edge: &BlockedEdge) -> bool {
+    self
+      .risk_name
+      .as_ref()
+      .map_or(true, |risk_name| edge.name.as_ref() == Some(risk_name))
+  }
+
+  /// Return the edge remaining after lifting, `None` if nothing remains blocked
+  pub fn apply(&self, edge: &BlockedEdge) -> Option<BlockedEdge> {
+    if !self.applies_to(edge) {
+      return Some(edge.clone());
+    }
+    let from = match &self.from {
+      Some(from) => from,
+      None => return None,
+    };
+    let alternatives = split_alternatives(&edge.from);
+    let remaining: Vec<&str> = alternatives
+      .iter()
+      .map(|a| a.as_str())
+      .filter(|a| *a != from.as_str())
+      .collect();
+    if remaining.is_empty() {
+      None
+    } else {
+      Some(BlockedEdge {
+        from: remaining.join("|"),
+        ..edge.clone()
+      })
+    }
+  }
+}
+
+/// Split regexp into top-level `|` alternatives
+fn split_alternatives(regexp: &str) -> Vec<String> {
+  let mut alternatives = vec![];
+  let mut current = String::new();
+  let mut depth = 0;
+  let mut in_class = false;
+  let mut escaped = false;
+  for c in regexp.chars() {
+    if escaped {
+      escaped = false;
+    } else {
+      match c {
+        '\\' => escaped = true,
+        '[' if !in_class => in_class = true,
+        ']' if in_class => in_class = false,
+        '(' if !in_class => depth += 1,
+        ')' if !in_class => depth -= 1,
+        '|' if !in_class && depth == 0 => {
+          alternatives.push(current);
+          current = String::new();
+          continue;
+        }
+        _ => {}
+      }
+    }
+    current.push(c);
+  }
+  alternatives.push(current);
+  alternatives
+}
+
+/// Merge `from` regexps as top-level alternatives, so blocking more sources never lifts existing blocks
+fn merge_from(existing: &str, requested: &str) -> String {
+  let mut alternatives = split_alternatives(existing);
+  for alternative in split_alternatives(requested) {
+    if !alternatives.contains(&alternative) {
+      alternatives.push(alternative);
+    }
+  }
+  if alternatives.iter().any(|a| a == ALL_VERSIONS_REGEXP) {
+    return ALL_VERSIONS_REGEXP.to_string();
+  }
+  alternatives.join("|")
+}
+
+/// Blocked edges file in the repo
+#[derive(Debug, Clone, PartialEq)]
+pub struct EdgeFile {
+  /// Path relative to the repo root
+  pub path: PathBuf,
+  pub contents: String,
+  pub edge: BlockedEdge,
+}
+
+/// Return an error if `from` is not a valid regexp
+pub fn validate_from(from: &str) -> Option<String> {
+  full_match_regex(from)
+    .err()
+    .map(|e| format!("from {:?} is not a valid regexp: {}", from, e))
+}
+
+/// Compile regexp matching whole strings only, as Cincinnati does
+fn full_match_regex(regexp: &str) -> Result<Regex, regex::Error> {
+  Regex::new(&format!("^(?:{})$", regexp))
+}
+
+/// Releases selected by an action
+#[derive(Debug, Clone)]
+pub enum ReleaseSelector {
+  /// Releases matching a semver range, e.g. `>=4.6.1, <4.6.5`
+  Range(VersionReq),
+  /// Releases fully matching a regexp
+  Regex(Regex),
+}
+
+impl ReleaseSelector {
+  /// Parse semver range
+  pub fn range(range: &str) -> Result<Self, String> {
+    VersionReq::parse(range)
+      .map(ReleaseSelector::Range)
+      .map_err(|e| format!("version range {:?} is invalid: {}", range, e))
+  }
+
+  /// Compile release regexp
+  pub fn regex(regexp: &str) -> Result<Self, String> {
+    full_match_regex(regexp)
+      .map(ReleaseSelector::Regex)
+      .map_err(|e| format!("version regexp {:?} is invalid: {}", regexp, e))
+  }
+
+  fn matches(&self, version: &Version) -> bool {
+    match self {
+      ReleaseSelector::Range(range) => range.matches(version),
+      ReleaseSelector::Regex(regex) => regex.is_match(&version.to_string()),
+    }
+  }
+}
+
+/// Channel file, only release versions are used
+#[derive(Debug, Deserialize)]
+struct Channel {
+  #[serde(default)]
+  versions: Vec<String>,
+}
+
+/// Return releases of each channel by channel name, sorted by version
+pub fn channels(path: &Path) -> Result<BTreeMap<String, Vec<Version>>, Error> {
+  let mut channels = BTreeMap::new();
+  for entry in fs::read_dir(path.join(CHANNELS_DIR))? {
+    let file_path = entry?.path();
+    if file_path.extension().map_or(true, |ext| ext != "yaml") {
+      continue;
+    }
+    let name = match file_path.file_stem().and_then(|stem| stem.to_str()) {
+      Some(name) => name.to_string(),
+      None => continue,
+    };
+    let contents = fs::read_to_string(&file_path)?;
+    let channel: Channel = serde_yaml::from_str(&contents)
+      .with_context(|| format!("{} is not a valid channel", file_path.display()))?;
+    let mut releases = BTreeSet::new();
+    for version in channel.versions {
+      match Version::parse(&version) {
+        Ok(version) => {
+          releases.insert(version);
+        }
+        Err(e) => debug!("Skipping {} in {}: {}", version, file_path.display(), e),
+      }
+    }
+    channels.insert(name, releases.into_iter().collect());
+  }
+  Ok(channels)
+}
+
+/// Return releases listed in channels files, sorted by version
+pub fn known_releases(path: &Path) -> Result<Vec<Version>, Error> {
+  let releases: BTreeSet<Version> = channels(path)?.values().flatten().cloned().collect();
+  Ok(releases.into_iter().collect())
+}
+
+/// Return known releases selected by the action, sorted by version
+pub fn matching_releases(path: &Path, selector: &ReleaseSelector) -> Result<Vec<String>, Error> {
+  Ok(
+    known_releases(path)?
+      .iter()
+      .filter(|version| selector.matches(version))
+      .map(|version| version.to_string())
+      .collect(),
+  )
+}
+
+/// Channel file path relative to the repo root
+pub fn channel_file(channel: &str) -> PathBuf {
+  Path::new(CHANNELS_DIR).join(format!("{}.yaml", channel))
+}
+
+/// Check the channel name, e.g. `fast-4.6`, can be used as a file name inside the channels directory
+pub fn is_valid_channel_name(channel: &str) -> bool {
+  !channel.is_empty()
+    && !channel.starts_with('.')
+    && !channel.contains("..")
+    && channel
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// Sort versions by semver precedence and drop duplicates.
+/// Versions which don't parse are kept after the others.
+fn sort_versions(versions: &mut Vec<String>) {
+  versions.sort_by(|a, b| match (Version::parse(a), Version::parse(b)) {
+    (Ok(va), Ok(vb)) => va.cmp(&vb).then_with(|| a.cmp(b)),
+    (Ok(_), Err(_)) => Ordering::Less,
+    (Err(_), Ok(_)) => Ordering::Greater,
+    (Err(_), Err(_)) => a.cmp(b),
+  });
+  versions.dedup();
+}
+
+/// Add the release to the channel, keeping the versions list sorted and deduplicated
+pub fn add_to_channel(path: &Path, channel: &str, version: &str) -> Result<Changes, Error> {
+  edit_channel(path, channel, |versions| {
+    if versions.iter().any(|v| v == version) {
+      return false;
+    }
+    versions.push(version.to_string());
+    true
+  })
+}
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
-struct BlockedEdge {
-  to: String,
-  from: String,
+/// Remove the release from the channel, keeping the versions list sorted and deduplicated
+pub fn remove_from_channel(path: &Path, channel: &str, version: &str) -> Result<Changes, Error> {
+  edit_channel(path, channel, |versions| {
+    let count = versions.len();
+    versions.retain(|v| v != version);
+    versions.len() != count
+  })
 }
 
-fn generate_yml_path(path: &Path, version: String) -> PathBuf {
-  [
-    path.to_str().unwrap().to_string(),
-    BLOCKED_DIR.to_string(),
-    format!("{}.yaml", version),
-  ]
-  .iter()
-  .collect()
+/// Apply `edit` to the versions of an existing channel, the file is only written if `edit` returns true
+fn edit_channel<F>(path: &Path, channel: &str, edit: F) -> Result<Changes, Error>
+where
+  F: FnOnce(&mut Vec<String>) -> bool,
+{
+  if !is_valid_channel_name(channel) {
+    bail!("channel name {:?} is invalid", channel);
+  }
+  let channel_path = channel_file(channel);
+  let contents = fs::read_to_string(path.join(&channel_path))
+    .with_context(|| format!("channel {} doesn't exist", channel))?;
+  let mut versions = serde_yaml::from_str::<Channel>(&contents)
+    .with_context(|| format!("{} is not a valid channel", channel_path.display()))?
+    .versions;
+  let mut changes = Changes::default();
+  if !edit(&mut versions) {
+    return Ok(changes);
+  }
+  sort_versions(&mut versions);
+  let updated = update_channel_versions(&contents, &versions)
+    .with_context(|| format!("{} can't be updated", channel_path.display()))?;
+  fs::write(path.join(&channel_path), &updated)?;
+  changes.modified.push(channel_path);
+  Ok(changes)
 }
 
-pub fn block_edge(path: &Path, version: String) -> Result<(), Error> {
-  let new_edge = BlockedEdge {
-    to: version.clone(),
-    from: ALL_VERSIONS_REGEXP.to_string(),
-  };
-  let f = fs::File::create(generate_yml_path(path, version))?;
-  serde_yaml::to_writer(f, &new_edge).map_err(|e| anyhow!(e.to_string()))
+/// Replace the versions list in channel file contents, keeping comments, other keys and list indentation
+fn update_channel_versions(existing: &str, versions: &[String]) -> Result<String, Error> {
+  let value: Value = serde_yaml::from_str(existing).context("file doesn't parse as YAML")?;
+  let mut expected = value
+    .as_mapping()
+    .ok_or_else(|| anyhow!("file is not a YAML mapping"))?
+    .clone();
+  let list = Value::Sequence(versions.iter().map(|v| Value::from(v.as_str())).collect());
+  match expected.get_mut(&Value::from("versions")) {
+    Some(have) => *have = list.clone(),
+    None => {
+      expected.insert(Value::from("versions"), list.clone());
+    }
+  }
+  let expected = Value::Mapping(expected);
+  let parses_as_expected =
+    |edited: &str| serde_yaml::from_str::<Value>(edited).ok().as_ref() == Some(&expected);
+
+  if let Some(edited) = edit_versions_list(existing, versions) {
+    if parses_as_expected(&edited) {
+      return Ok(edited);
+    }
+  }
+  if let Some(edited) = edit_top_level_keys(existing, &[("versions", Some(list))]) {
+    if parses_as_expected(&edited) {
+      return Ok(edited);
+    }
+  }
+  serde_yaml::to_string(&expected).map_err(|e| anyhow!(e.to_string()))
 }
 
-pub fn unblock_edge(path: &Path, version: String) -> Result<(), Error> {
-  let edge_path = generate_yml_path(path, version);
-  match edge_path.exists() {
-    true => fs::remove_file(edge_path).map_err(|e| anyhow!(e.to_string())),
-    false => Ok(()),
+/// Rewrite the `versions` block sequence line by line, using the indentation of its current items
+fn edit_versions_list(existing: &str, versions: &[String]) -> Option<String> {
+  let lines: Vec<&str> = existing.lines().collect();
+  let start = lines
+    .iter()
+    .position(|line| top_level_key(line).as_deref() == Some("versions"))?;
+  let mut end = lines
+    .iter()
+    .skip(start + 1)
+    .position(|line| top_level_key(line).is_some())
+    .map_or(lines.len(), |i| start + 1 + i);
+  // Comments and blank lines before the next key belong to that key
+  while end > start + 1 && is_blank_or_comment(lines[end - 1]) {
+    end -= 1;
+  }
+  let indent = lines[start + 1..end]
+    .iter()
+    .find(|line| line.trim_start().starts_with('-'))
+    .map_or("", |line| &line[..line.len() - line.trim_start().len()]);
+
+  let mut output: Vec<String> = lines[..start].iter().map(|l| l.to_string()).collect();
+  if versions.is_empty() {
+    output.push("versions: []".to_string());
+  } else {
+    output.push("versions:".to_string());
+    output.extend(versions.iter().map(|v| format!("{}- {}", indent, v)));
   }
+  output.extend(lines[end..].iter().map(|l| l.to_string()));
+  Some(output.join("\n") + "\n")
+}
+
+/// Paths changed by a graph-data edit, relative to the repo root
+#[derive(Debug, Default, PartialEq)]
+pub struct Changes {
+  pub created: Vec<PathBuf>,
+  pub modified: Vec<PathBuf>,
+  pub removed: Vec<PathBuf>,
+  /// Blocked edges files for the version left after the edit
+  pub remaining: Vec<EdgeFile>,
+}
+
+impl Changes {
+  /// No files were touched
+  pub fn is_empty(&self) -> bool {
+    self.created.is_empty() && self.modified.is_empty() && self.removed.is_empty()
+  }
+
+  /// Merge changes of another edit
+  pub fn extend(&mut self, other: Changes) {
+    self.created.extend(other.created);
+    self.modified.extend(other.modified);
+    self.removed.extend(other.removed);
+    self.remaining.extend(other.remaining);
+  }
+
+  /// Created and modified paths, which need to be staged
+  pub fn updated(&self) -> Vec<PathBuf> {
+    self
+      .created
+      .iter()
+      .chain(self.modified.iter())
+      .cloned()
+      .collect()
+  }
+}
+
+/// Blocked edges file path relative to the repo root
+pub fn edge_file(version: &str) -> PathBuf {
+  Path::new(BLOCKED_DIR).join(format!("{}.yaml", version))
+}
+
+/// Check the version can be used in a file name inside the blocked edges directory
+fn is_safe_file_stem(version: &str) -> bool {
+  !version.is_empty()
+    && !version.contains("..")
+    && !version.contains(|c: char| c == '/' || c == '\\' || c.is_control())
+}
+
+/// File path for a new blocked edges file, named after the risk for conditional edges
+pub fn edge_file_name(edge: &BlockedEdge) -> PathBuf {
+  match &edge.name {
+    Some(name) => Path::new(BLOCKED_DIR).join(format!("{}-{}.yaml", edge.to, kebab_case(name))),
+    None => edge_file(&edge.to),
+  }
+}
+
+/// Check whether the file name follows `{version}.yaml` or `{version}-{risk}.yaml` naming
+pub fn is_conventional_file_name(name: &str, version: &str) -> bool {
+  name == format!("{}.yaml", version)
+    || (name.starts_with(&format!("{}-", version)) && name.ends_with(".yaml"))
+}
+
+/// Convert CamelCase risk name to kebab-case, e.g. `OVNBug` to `ovn-bug`
+fn kebab_case(name: &str) -> String {
+  let chars: Vec<char> = name.chars().collect();
+  let mut kebab = String::new();
+  for (i, c) in chars.iter().enumerate() {
+    if i > 0 && c.is_ascii_uppercase() {
+      let prev = chars[i - 1];
+      let next_lower = chars.get(i + 1).map_or(false, |n| n.is_ascii_lowercase());
+      if prev.is_ascii_lowercase() || prev.is_ascii_digit() || next_lower {
+        kebab.push('-');
+      }
+    }
+    kebab.push(c.to_ascii_lowercase());
+  }
+  kebab
+}
+
+/// Find blocked edges files blocking upgrades to the version, regardless of file names
+pub fn find_edge_files(path: &Path, version: &str) -> Result<Vec<EdgeFile>, Error> {
+  let mut files = vec![];
+  for entry in fs::read_dir(path.join(BLOCKED_DIR))? {
+    let entry = entry?;
+    let file_path = entry.path();
+    if file_path.extension().map_or(true, |ext| ext != "yaml") {
+      continue;
+    }
+    let contents = fs::read_to_string(&file_path)?;
+    match serde_yaml::from_str::<BlockedEdge>(&contents) {
+      Ok(edge) if edge.to == version => files.push(EdgeFile {
+        path: Path::new(BLOCKED_DIR).join(entry.file_name()),
+        contents,
+        edge,
+      }),
+      Ok(_) => {}
+      Err(e) => debug!("Skipping {}: {}", file_path.display(), e),
+    }
+  }
+  files.sort_by(|a, b| a.path.cmp(&b.path));
+  Ok(files)
+}
+
+/// Check whether blocked edges file contents already declare the edge
+pub fn contains_edge(contents: &str, edge: &BlockedEdge) -> bool {
+  match serde_yaml::from_str::<BlockedEdge>(contents) {
+    Ok(existing) => existing == *edge,
+    Err(_) => false,
+  }
+}
+
+/// Check whether blocked edges file contents may block updates to the version,
+/// contents which don't parse as a blocked edge are assumed to
+pub fn blocks_version(contents: &str, version: &str) -> bool {
+  match serde_yaml::from_str::<BlockedEdge>(contents) {
+    Ok(edge) => edge.to == version,
+    Err(_) => true,
+  }
+}
+
+/// Return all blocked edges in the repo, files which don't parse are skipped
+pub fn blocked_edges(path: &Path) -> Result<Vec<BlockedEdge>, Error> {
+  let mut edges = vec![];
+  for entry in fs::read_dir(path.join(BLOCKED_DIR))? {
+    let file_path = entry?.path();
+    if file_path.extension().map_or(true, |ext| ext != "yaml") {
+      continue;
+    }
+    let contents = fs::read_to_string(&file_path)?;
+    match serde_yaml::from_str::<BlockedEdge>(&contents) {
+      Ok(edge) => edges.push(edge),
+      Err(e) => debug!("Skipping {}: {}", file_path.display(), e),
+    }
+  }
+  Ok(edges)
+}
+
+/// Return paths of YAML files in the directory, sorted by name
+fn yaml_files(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+  let mut files = vec![];
+  for entry in fs::read_dir(dir).with_context(|| format!("{} can't be read", dir.display()))? {
+    let file_path = entry?.path();
+    if file_path.extension().map_or(false, |ext| ext == "yaml") {
+      files.push(file_path);
+    }
+  }
+  files.sort();
+  Ok(files)
+}
+
+/// Kind of problem found in graph data
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ViolationKind {
+  /// Channel file doesn't parse
+  InvalidChannel,
+  /// Channel lists a version which is not a release version
+  InvalidVersion,
+  /// Blocked edges file doesn't parse
+  InvalidBlockedEdgeFile,
+  /// Blocked edge has an invalid `from` regexp or risk
+  InvalidBlockedEdge,
+  /// Blocked edge `to` is not listed in any channel
+  UnknownRelease,
+  /// Several files declare the same risk for a release
+  DuplicateRisk,
+}
+
+/// Problem found in graph data
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+  pub kind: ViolationKind,
+  /// Files with the problem, relative to the repo root
+  pub files: Vec<String>,
+  /// Release the problem is about
+  pub version: Option<String>,
+  /// Risk the problem is about
+  pub risk: Option<String>,
+  /// Parse or validation error
+  pub details: Option<String>,
+}
+
+impl Violation {
+  fn new(kind: ViolationKind, file: String) -> Self {
+    Violation {
+      kind,
+      files: vec![file],
+      version: None,
+      risk: None,
+      details: None,
+    }
+  }
+
+  /// Both describe the same problem. Files of a duplicate risk are not compared,
+  /// so adding another file for the risk doesn't make it a new problem.
+  pub fn same_problem(&self, other: &Violation) -> bool {
+    self.kind == other.kind
+      && self.version == other.version
+      && self.risk == other.risk
+      && self.details == other.details
+      && (self.kind == ViolationKind::DuplicateRisk || self.files == other.files)
+  }
+}
+
+impl fmt::Display for Violation {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let files = self.files.join(", ");
+    let version = self.version.as_deref().unwrap_or_default();
+    let details = self.details.as_deref().unwrap_or_default();
+    match self.kind {
+      ViolationKind::InvalidChannel => write!(f, "{}: not a valid channel: {}", files, details),
+      ViolationKind::InvalidVersion => write!(
+        f,
+        "{}: version {:?} is invalid: {}",
+        files, version, details
+      ),
+      ViolationKind::InvalidBlockedEdgeFile => {
+        write!(f, "{}: not a valid blocked edge: {}", files, details)
+      }
+      ViolationKind::InvalidBlockedEdge => write!(f, "{}: {}", files, details),
+      ViolationKind::UnknownRelease => {
+        write!(f, "{}: {} is not listed in any channel", files, version)
+      }
+      ViolationKind::DuplicateRisk => write!(
+        f,
+        "{} all block {} for {}",
+        files,
+        version,
+        self.risk.as_deref().unwrap_or_default()
+      ),
+    }
+  }
+}
+
+/// Check channels and blocked edges files, returning all problems found
+pub fn validate_tree(path: &Path) -> Result<Vec<Violation>, Error> {
+  let mut violations = vec![];
+  let mut releases = BTreeSet::new();
+  for file_path in yaml_files(&path.join(CHANNELS_DIR))? {
+    let relative = file_path.strip_prefix(path)?.display().to_string();
+    let contents = fs::read_to_string(&file_path)?;
+    match serde_yaml::from_str::<Channel>(&contents) {
+      Ok(channel) => {
+        for version in channel.versions {
+          if let Err(e) = Version::parse(&version) {
+            violations.push(Violation {
+              version: Some(version.clone()),
+              details: Some(e.to_string()),
+              ..Violation::new(ViolationKind::InvalidVersion, relative.clone())
+            });
+          }
+          releases.insert(version);
+        }
+      }
+      Err(e) => violations.push(Violation {
+        details: Some(e.to_string()),
+        ..Violation::new(ViolationKind::InvalidChannel, relative)
+      }),
+    }
+  }
+
+  let mut files_by_risk: BTreeMap<(String, String), Vec<String>> = BTreeMap::new();
+  for file_path in yaml_files(&path.join(BLOCKED_DIR))? {
+    let relative = file_path.strip_prefix(path)?.display().to_string();
+    let contents = fs::read_to_string(&file_path)?;
+    let edge = match serde_yaml::from_str::<BlockedEdge>(&contents) {
+      Ok(edge) => edge,
+      Err(e) => {
+        violations.push(Violation {
+          details: Some(e.to_string()),
+          ..Violation::new(ViolationKind::InvalidBlockedEdgeFile, relative)
+        });
+        continue;
+      }
+    };
+    for error in edge.validate() {
+      violations.push(Violation {
+        version: Some(edge.to.clone()),
+        risk: edge.name.clone(),
+        details: Some(error),
+        ..Violation::new(ViolationKind::InvalidBlockedEdge, relative.clone())
+      });
+    }
+    if !releases.contains(&edge.to) {
+      violations.push(Violation {
+        version: Some(edge.to.clone()),
+        risk: edge.name.clone(),
+        ..Violation::new(ViolationKind::UnknownRelease, relative.clone())
+      });
+    }
+    // Several files may block a release unconditionally, e.g. from different releases
+    if let Some(name) = edge.name {
+      files_by_risk
+        .entry((edge.to, name))
+        .or_default()
+        .push(relative);
+    }
+  }
+  for ((to, name), files) in files_by_risk {
+    if files.len() > 1 {
+      violations.push(Violation {
+        kind: ViolationKind::DuplicateRisk,
+        files,
+        version: Some(to),
+        risk: Some(name),
+        details: None,
+      });
+    }
+  }
+  Ok(violations)
+}
+
+/// Return number of blocked edges files in the repo
+pub fn count_blocked_edge_files(path: &Path) -> Result<usize, Error> {
+  let count = fs::read_dir(path.join(BLOCKED_DIR))?
+    .filter_map(|entry| entry.ok())
+    .filter(|entry| entry.path().extension().map_or(false, |ext| ext == "yaml"))
+    .count();
+  Ok(count)
+}
+
+pub fn block_edge(path: &Path, new_edge: &BlockedEdge) -> Result<Changes, Error> {
+  let errors = new_edge.validate();
+  if !errors.is_empty() {
+    bail!("invalid blocked edge: {}", errors.join(", "));
+  }
+  let mut files = find_edge_files(path, &new_edge.to)?;
+  let new_path = edge_file_name(new_edge);
+  // Update the file declaring the same risk, preferring the conventionally named one.
+  // `from` is merged, so other blocks stay in place.
+  let same_risk = |f: &EdgeFile| f.edge.name == new_edge.name;
+  let existing = files
+    .iter()
+    .position(|f| f.path == new_path && same_risk(f))
+    .or_else(|| files.iter().position(same_risk));
+  let mut changes = Changes::default();
+  match existing {
+    Some(i) => {
+      let file = &mut files[i];
+      let edge = BlockedEdge {
+        from: merge_from(&file.edge.from, &new_edge.from),
+        ..new_edge.clone()
+      };
+      if let Some(updated) = update_edge_file(&file.contents, &edge)
+        .with_context(|| format!("{} can't be updated", file.path.display()))?
+      {
+        fs::write(path.join(&file.path), &updated)?;
+        changes.modified.push(file.path.clone());
+        file.contents = updated;
+        file.edge = edge;
+      }
+    }
+    None => {
+      let new_path = if files.iter().any(|f| f.path == new_path) {
+        // The name is taken by another risk of the version, which must stay as it is
+        unique_file_name(path, &new_path)
+      } else if path.join(&new_path).exists() {
+        bail!(
+          "{} exists, but is not a blocked edge for {}",
+          new_path.display(),
+          new_edge.to
+        );
+      } else {
+        new_path
+      };
+      let contents = serde_yaml::to_string(new_edge).map_err(|e| anyhow!(e.to_string()))?;
+      fs::write(path.join(&new_path), &contents)?;
+      changes.created.push(new_path.clone());
+      files.push(EdgeFile {
+        path: new_path,
+        contents,
+        edge: new_edge.clone(),
+      });
+    }
+  }
+  changes.remaining = files;
+  Ok(changes)
+}
+
+/// Return the first `{stem}-{n}.yaml` sibling of the file which doesn't exist yet
+fn unique_file_name(path: &Path, file: &Path) -> PathBuf {
+  let stem = file
+    .file_stem()
+    .map(|stem| stem.to_string_lossy().into_owned())
+    .unwrap_or_default();
+  (2..)
+    .map(|n| file.with_file_name(format!("{}-{}.yaml", stem, n)))
+    .find(|candidate| !path.join(candidate).exists())
+    .unwrap()
+}
+
+/// Set edge keys in existing blocked edges file contents, keeping comments, unknown keys
+/// and key order. Returns `None` if the file already declares the edge.
+fn update_edge_file(existing: &str, edge: &BlockedEdge) -> Result<Option<String>, Error> {
+  let value: Value = serde_yaml::from_str(existing).context("file doesn't parse as YAML")?;
+  let mapping = value
+    .as_mapping()
+    .ok_or_else(|| anyhow!("file is not a YAML mapping"))?;
+  serde_yaml::from_value::<BlockedEdge>(value.clone()).context("file is not a blocked edge")?;
+
+  let desired = serde_yaml::to_value(edge).map_err(|e| anyhow!(e.to_string()))?;
+  let updates: Vec<(&str, Option<Value>)> = EDGE_KEYS
+    .iter()
+    .map(|key| (*key, desired.get(*key).cloned()))
+    .filter(|(key, want)| mapping.get(&Value::from(*key)) != want.as_ref())
+    .collect();
+  if updates.is_empty() {
+    return Ok(None);
+  }
+
+  let mut expected = mapping.clone();
+  for (key, want) in &updates {
+    let key = Value::from(*key);
+    match (expected.get_mut(&key), want) {
+      (Some(have), Some(want)) => *have = want.clone(),
+      (None, Some(want)) => {
+        expected.insert(key, want.clone());
+      }
+      (_, None) => {
+        expected.remove(&key);
+      }
+    }
+  }
+  let expected = Value::Mapping(expected);
+
+  // Edit changed keys in place, rewrite the whole mapping if that's not possible
+  if let Some(edited) = edit_top_level_keys(existing, &updates) {
+    if serde_yaml::from_str::<Value>(&edited).ok().as_ref() == Some(&expected) {
+      return Ok(Some(edited));
+    }
+  }
+  serde_yaml::to_string(&expected)
+    .map(Some)
+    .map_err(|e| anyhow!(e.to_string()))
+}
+
+/// Return the key if the line starts a top-level block mapping entry
+fn top_level_key(line: &str) -> Option<String> {
+  if line.is_empty() || line.starts_with(|c: char| c.is_whitespace() || c == '#' || c == '-') {
+    return None;
+  }
+  let (key, _) = line.split_at(line.find(':')?);
+  Some(
+    key
+      .trim()
+      .trim_matches(|c: char| c == '"' || c == '\'')
+      .to_string(),
+  )
+}
+
+fn is_blank_or_comment(line: &str) -> bool {
+  let line = line.trim_start();
+  line.is_empty() || line.starts_with('#')
+}
+
+/// Replace, remove or append top-level keys of a block mapping line by line,
+/// so comments and formatting of other keys are untouched
+fn edit_top_level_keys(existing: &str, updates: &[(&str, Option<Value>)]) -> Option<String> {
+  let lines: Vec<&str> = existing.lines().collect();
+  let mut spans: Vec<(String, usize, usize)> = vec![];
+  for (i, line) in lines.iter().enumerate() {
+    if let Some(key) = top_level_key(line) {
+      if let Some(last) = spans.last_mut() {
+        last.2 = i;
+      }
+      spans.push((key, i, lines.len()));
+    }
+  }
+  // Comments and blank lines before a key belong to that key
+  for span in spans.iter_mut() {
+    while span.2 > span.1 + 1 && is_blank_or_comment(lines[span.2 - 1]) {
+      span.2 -= 1;
+    }
+  }
+
+  let mut replaced: Vec<Option<Vec<String>>> = vec![None; lines.len()];
+  let mut skipped = vec![false; lines.len()];
+  let mut appended = vec![];
+  for (key, want) in updates {
+    let rendered = match want {
+      Some(value) => Some(render_entry(key, value)?),
+      None => None,
+    };
+    match (spans.iter().find(|(k, _, _)| k.as_str() == *key), rendered) {
+      (Some((_, start, end)), rendered) => {
+        for skip in skipped.iter_mut().take(*end).skip(*start) {
+          *skip = true;
+        }
+        replaced[*start] = rendered;
+      }
+      (None, Some(rendered)) => appended.extend(rendered),
+      (None, None) => {}
+    }
+  }
+
+  let mut output = vec![];
+  for (i, line) in lines.iter().enumerate() {
+    if let Some(rendered) = &replaced[i] {
+      output.extend(rendered.iter().cloned());
+    }
+    if !skipped[i] {
+      output.push(line.to_string());
+    }
+  }
+  output.extend(appended);
+  Some(output.join("\n") + "\n")
+}
+
+/// Render a single `key: value` entry as YAML lines
+fn render_entry(key: &str, value: &Value) -> Option<Vec<String>> {
+  let mut entry = Mapping::new();
+  entry.insert(Value::from(key), value.clone());
+  let rendered = serde_yaml::to_string(&entry).ok()?;
+  Some(
+    rendered
+      .lines()
+      .filter(|line| *line != "---")
+      .map(|line| line.to_string())
+      .collect(),
+  )
+}
+
+pub fn unblock_edge(path: &Path, version: String, unblock: &Unblock) -> Result<Changes, Error> {
+  if !is_safe_file_stem(&version) {
+    bail!("version {:?} can't be used as a file name", version);
+  }
+  let files = find_edge_files(path, &version)?;
+  // Lifting a risk or an alternative which no blocked edge declares would leave the version blocked
+  if let Some(risk_name) = &unblock.risk_name {
+    if !files.is_empty() && !files.iter().any(|file| unblock.applies_to(&file.edge)) {
+      let current: Vec<String> = files
+        .iter()
+        .map(|file| {
+          let risk = file.edge.name.as_deref().unwrap_or("unconditional");
+          format!("{}: {}", file.path.display(), risk)
+        })
+        .collect();
+      return Err(
+        AppError::InvalidAction(format!(
+          "risk {:?} is not blocked for {}, current risks: {}",
+          risk_name,
+          version,
+          current.join(", ")
+        ))
+        .into(),
+      );
+    }
+  }
+  if let Some(from) = &unblock.from {
+    let selected: Vec<&EdgeFile> = files
+      .iter()
+      .filter(|file| unblock.applies_to(&file.edge))
+      .collect();
+    let found = selected
+      .iter()
+      .any(|file| split_alternatives(&file.edge.from).contains(from));
+    if !selected.is_empty() && !found {
+      let current: Vec<String> = selected
+        .iter()
+        .map(|file| format!("{}: {}", file.path.display(), file.edge.from))
+        .collect();
+      return Err(
+        AppError::InvalidAction(format!(
+          "from alternative {:?} is not blocked for {}, current from values: {}",
+          from,
+          version,
+          current.join(", ")
+        ))
+        .into(),
+      );
+    }
+  }
+  let mut changes = Changes::default();
+  for mut file in files {
+    match unblock.apply(&file.edge) {
+      None => {
+        fs::remove_file(path.join(&file.path)).map_err(|e| anyhow!(e.to_string()))?;
+        changes.removed.push(file.path);
+      }
+      Some(remaining) => {
+        if let Some(updated) = update_edge_file(&file.contents, &remaining)
+          .with_context(|| format!("{} can't be updated", file.path.display()))?
+        {
+          fs::write(path.join(&file.path), &updated)?;
+          changes.modified.push(file.path.clone());
+          file.contents = updated;
+          file.edge = remaining;
+        }
+        changes.remaining.push(file);
+      }
+    }
+  }
+  // `{version}.yaml` is removed on full unblock even if it doesn't parse
+  let default_path = edge_file(&version);
+  if *unblock == Unblock::default()
+    && !changes.removed.contains(&default_path)
+    && path.join(&default_path).exists()
+  {
+    fs::remove_file(path.join(&default_path)).map_err(|e| anyhow!(e.to_string()))?;
+    changes.removed.push(default_path);
+  }
+  Ok(changes)
 }
 
 #[cfg(test)]
@@ -44,6 +1100,10 @@ mod tests {
   use std::io::Write;
   use tempfile::tempdir;
 
+  fn generate_yml_path(path: &Path, version: String) -> PathBuf {
+    path.join(edge_file(&version))
+  }
+
   #[test]
   fn block_edge_new_file() {
     let tmpdir = tempdir().unwrap();
@@ -51,8 +1111,11 @@ mod tests {
     std::fs::create_dir(base_path.join(BLOCKED_DIR)).unwrap();
     let version = "0.0.0".to_string();
 
-    let result = block_edge(base_path, version.clone());
-    assert!(result.is_ok());
+    let result = block_edge(
+      base_path,
+      &BlockedEdge::new(&version, ALL_VERSIONS_REGEXP, None),
+    );
+    assert_eq!(result.unwrap().created, vec![edge_file(&version)]);
 
     let f = fs::File::open(generate_yml_path(base_path, version.clone()));
     let edge: BlockedEdge = serde_yaml::from_reader(f.unwrap()).unwrap();
@@ -72,13 +1135,133 @@ mod tests {
     f.write_all(b"Hello, world!").unwrap();
     drop(f);
 
-    let result = block_edge(base_path, version.clone());
-    assert!(result.is_ok());
+    let result = block_edge(
+      base_path,
+      &BlockedEdge::new(&version, ALL_VERSIONS_REGEXP, None),
+    );
+    assert!(result.is_err());
+    assert_eq!(fs::read_to_string(expected_path).unwrap(), "Hello, world!");
+  }
 
-    f = fs::File::open(expected_path.clone()).unwrap();
-    let edge: BlockedEdge = serde_yaml::from_reader(f).unwrap();
-    assert_eq!(edge.to, version.clone());
-    assert_eq!(edge.from, ALL_VERSIONS_REGEXP);
+  #[test]
+  fn block_edge_preserves_existing_file() {
+    let tmpdir = tempdir().unwrap();
+    let base_path = Path::new(tmpdir.path());
+    std::fs::create_dir(base_path.join(BLOCKED_DIR)).unwrap();
+    let version = "0.0.0".to_string();
+    let expected_path = generate_yml_path(base_path, version.clone());
+
+    let existing =
+      "# Blocked due to bug 1\nfrom: 4\\.5\\..*\nto: 0.0.0\n# Keep this\nfixedIn: 0.0.1\n";
+    fs::write(&expected_path, existing).unwrap();
+
+    let edge = BlockedEdge::new(&version, ALL_VERSIONS_REGEXP, None);
+    let result = block_edge(base_path, &edge);
+    assert_eq!(result.unwrap().modified, vec![edge_file(&version)]);
+    let content = fs::read_to_string(&expected_path).unwrap();
+    assert!(content.starts_with("# Blocked due to bug 1\nfrom: "));
+    assert!(content.ends_with("\nto: 0.0.0\n# Keep this\nfixedIn: 0.0.1\n"));
+    assert!(contains_edge(&content, &edge));
+
+    let result = block_edge(base_path, &edge);
+    assert!(result.unwrap().is_empty());
+  }
+
+  #[test]
+  fn block_edge_merges_from() {
+    let tmpdir = tempdir().unwrap();
+    let base_path = Path::new(tmpdir.path());
+    std::fs::create_dir(base_path.join(BLOCKED_DIR)).unwrap();
+    let expected_path = generate_yml_path(base_path, "0.0.0".to_string());
+    fs::write(&expected_path, "to: 0.0.0\nfrom: 4\\.5\\..*\n").unwrap();
+
+    // Disjoint sources are added, 4.5 stays blocked
+    let result = block_edge(base_path, &BlockedEdge::new("0.0.0", "4\\.4\\..*", None));
+    assert_eq!(result.unwrap().modified, vec![edge_file("0.0.0")]);
+    assert_eq!(read_from(&expected_path), "4\\.5\\..*|4\\.4\\..*");
+
+    // Sources already blocked don't change the file
+    let result = block_edge(base_path, &BlockedEdge::new("0.0.0", "4\\.4\\..*", None));
+    assert!(result.unwrap().is_empty());
+
+    // Blocking all sources replaces narrower alternatives, narrower blocks keep it
+    let result = block_edge(
+      base_path,
+      &BlockedEdge::new("0.0.0", ALL_VERSIONS_REGEXP, None),
+    );
+    assert_eq!(result.unwrap().modified, vec![edge_file("0.0.0")]);
+    let result = block_edge(base_path, &BlockedEdge::new("0.0.0", "4\\.3\\..*", None));
+    assert!(result.unwrap().is_empty());
+    assert_eq!(read_from(&expected_path), ALL_VERSIONS_REGEXP);
+  }
+
+  fn read_from(path: &Path) -> String {
+    let edge: BlockedEdge = serde_yaml::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+    edge.from
+  }
+
+  #[test]
+  fn block_edge_keeps_other_unconditional_blocks() {
+    let tmpdir = tempdir().unwrap();
+    let base_path = Path::new(tmpdir.path());
+    std::fs::create_dir(base_path.join(BLOCKED_DIR)).unwrap();
+    let storage_path = base_path.join(BLOCKED_DIR).join("storage-bug.yaml");
+    fs::write(&storage_path, "to: 0.0.0\nfrom: 4\\.5\\..*\n").unwrap();
+
+    let result = block_edge(base_path, &BlockedEdge::new("0.0.0", "4\\.4\\..*", None));
+    assert_eq!(
+      result.unwrap().modified,
+      vec![Path::new(BLOCKED_DIR).join("storage-bug.yaml")]
+    );
+    assert_eq!(read_from(&storage_path), "4\\.5\\..*|4\\.4\\..*");
+
+    // The conventionally named file is preferred over other unconditional blocks
+    let expected_path = generate_yml_path(base_path, "0.0.0".to_string());
+    fs::write(&expected_path, "to: 0.0.0\nfrom: 4\\.3\\..*\n").unwrap();
+    let result = block_edge(base_path, &BlockedEdge::new("0.0.0", "4\\.2\\..*", None));
+    assert_eq!(result.unwrap().modified, vec![edge_file("0.0.0")]);
+    assert_eq!(read_from(&expected_path), "4\\.3\\..*|4\\.2\\..*");
+    assert_eq!(read_from(&storage_path), "4\\.5\\..*|4\\.4\\..*");
+  }
+
+  #[test]
+  fn block_edge_updates_risk_in_flow_mapping() {
+    let tmpdir = tempdir().unwrap();
+    let base_path = Path::new(tmpdir.path());
+    std::fs::create_dir(base_path.join(BLOCKED_DIR)).unwrap();
+    let version = "0.0.0".to_string();
+    let expected_path = generate_yml_path(base_path, version.clone());
+
+    fs::write(
+      &expected_path,
+      "{to: 0.0.0, from: .*, fixedIn: 0.0.1, name: SomeRisk, url: 'https://example.com', \
+       message: Old, matchingRules: [{type: Always}]}",
+    )
+    .unwrap();
+
+    let edge = BlockedEdge::new(&version, ALL_VERSIONS_REGEXP, Some(&risk()));
+    block_edge(base_path, &edge).unwrap();
+    let content = fs::read_to_string(&expected_path).unwrap();
+    assert!(contains_edge(&content, &edge));
+    let value: Value = serde_yaml::from_str(&content).unwrap();
+    let keys: Vec<&str> = value
+      .as_mapping()
+      .unwrap()
+      .iter()
+      .map(|(k, _)| k.as_str().unwrap())
+      .collect();
+    assert_eq!(
+      keys,
+      vec![
+        "to",
+        "from",
+        "fixedIn",
+        "name",
+        "url",
+        "message",
+        "matchingRules"
+      ]
+    );
   }
 
   #[test]
@@ -88,8 +1271,8 @@ mod tests {
     std::fs::create_dir(base_path.join(BLOCKED_DIR)).unwrap();
     let version = "0.0.0".to_string();
 
-    let result = unblock_edge(base_path, version.clone());
-    assert!(result.is_ok());
+    let result = unblock_edge(base_path, version.clone(), &Unblock::default());
+    assert!(result.unwrap().is_empty());
     assert!(!generate_yml_path(base_path, version.clone()).exists());
   }
 
@@ -105,8 +1288,438 @@ mod tests {
     f.write_all(b"Hello, world!").unwrap();
     drop(f);
 
-    let result = unblock_edge(base_path, version.clone());
-    assert!(result.is_ok());
+    let result = unblock_edge(base_path, version.clone(), &Unblock::default());
+    assert_eq!(result.unwrap().removed, vec![edge_file(&version)]);
     assert!(!expected_path.exists());
   }
+
+  #[test]
+  fn unblock_edge_from_alternative() {
+    let tmpdir = tempdir().unwrap();
+    let base_path = Path::new(tmpdir.path());
+    std::fs::create_dir(base_path.join(BLOCKED_DIR)).unwrap();
+    let version = "0.0.0".to_string();
+    let expected_path = generate_yml_path(base_path, version.clone());
+    fs::write(
+      &expected_path,
+      "# Keep this\nto: 0.0.0\nfrom: 4\\.4\\..*|4\\.(5|6)\\..*\n",
+    )
+    .unwrap();
+
+    let unblock = Unblock {
+      from: Some("4\\.4\\..*".to_string()),
+      ..Default::default()
+    };
+    let changes = unblock_edge(base_path, version.clone(), &unblock).unwrap();
+    assert_eq!(changes.modified, vec![edge_file(&version)]);
+    assert_eq!(changes.remaining[0].edge.from, "4\\.(5|6)\\..*");
+    let content = fs::read_to_string(&expected_path).unwrap();
+    assert!(content.starts_with("# Keep this\nto: 0.0.0\n"));
+
+    // Lifting an alternative which isn't blocked is rejected
+    let err = unblock_edge(base_path, version.clone(), &unblock).unwrap_err();
+    assert_eq!(
+      err.downcast::<AppError>().unwrap(),
+      AppError::InvalidAction(
+        "from alternative \"4\\\\.4\\\\..*\" is not blocked for 0.0.0, \
+         current from values: blocked-edges/0.0.0.yaml: 4\\.(5|6)\\..*"
+          .to_string()
+      )
+    );
+    assert_eq!(fs::read_to_string(&expected_path).unwrap(), content);
+
+    // An alternative of a wider regexp can't be lifted on its own
+    let narrower = Unblock {
+      from: Some("4\\.5\\..*".to_string()),
+      ..Default::default()
+    };
+    assert!(unblock_edge(base_path, version.clone(), &narrower).is_err());
+    assert!(expected_path.exists());
+
+    let unblock = Unblock {
+      from: Some("4\\.(5|6)\\..*".to_string()),
+      ..Default::default()
+    };
+    let changes = unblock_edge(base_path, version.clone(), &unblock).unwrap();
+    assert_eq!(changes.removed, vec![edge_file(&version)]);
+    assert!(changes.remaining.is_empty());
+
+    // Sources matched by `.*` aren't a separate alternative either
+    fs::write(&expected_path, "to: 0.0.0\nfrom: .*\n").unwrap();
+    assert!(unblock_edge(base_path, version.clone(), &narrower).is_err());
+    assert!(expected_path.exists());
+  }
+
+  #[test]
+  fn unblock_edge_risk_name() {
+    let tmpdir = tempdir().unwrap();
+    let base_path = Path::new(tmpdir.path());
+    std::fs::create_dir(base_path.join(BLOCKED_DIR)).unwrap();
+    let version = "0.0.0".to_string();
+    let edge = BlockedEdge::new(&version, ALL_VERSIONS_REGEXP, Some(&risk()));
+    block_edge(base_path, &edge).unwrap();
+
+    let other = Unblock {
+      risk_name: Some("OtherRisk".to_string()),
+      ..Default::default()
+    };
+    let err = unblock_edge(base_path, version.clone(), &other).unwrap_err();
+    assert_eq!(
+      err.downcast::<AppError>().unwrap(),
+      AppError::InvalidAction(
+        "risk \"OtherRisk\" is not blocked for 0.0.0, \
+         current risks: blocked-edges/0.0.0-some-risk.yaml: SomeRisk"
+          .to_string()
+      )
+    );
+    assert!(base_path.join(edge_file_name(&edge)).exists());
+
+    let unblock = Unblock {
+      risk_name: Some("SomeRisk".to_string()),
+      ..Default::default()
+    };
+    let changes = unblock_edge(base_path, version.clone(), &unblock).unwrap();
+    assert_eq!(changes.removed, vec![edge_file_name(&edge)]);
+  }
+
+  #[test]
+  fn multiple_files_per_version() {
+    let tmpdir = tempdir().unwrap();
+    let base_path = Path::new(tmpdir.path());
+    std::fs::create_dir(base_path.join(BLOCKED_DIR)).unwrap();
+    let version = "0.0.0".to_string();
+    fs::write(
+      base_path.join(BLOCKED_DIR).join("storage-bug.yaml"),
+      "to: 0.0.0\nfrom: .*\n",
+    )
+    .unwrap();
+    fs::write(
+      base_path.join(BLOCKED_DIR).join("0.0.1.yaml"),
+      "to: 0.0.1\nfrom: .*\n",
+    )
+    .unwrap();
+
+    // Unconditional block is already declared in a differently named file
+    let unconditional = BlockedEdge::new(&version, ALL_VERSIONS_REGEXP, None);
+    let changes = block_edge(base_path, &unconditional).unwrap();
+    assert!(changes.is_empty());
+    assert_eq!(
+      changes.remaining[0].path,
+      Path::new(BLOCKED_DIR).join("storage-bug.yaml")
+    );
+
+    let conditional = BlockedEdge::new(&version, ALL_VERSIONS_REGEXP, Some(&risk()));
+    let changes = block_edge(base_path, &conditional).unwrap();
+    assert_eq!(changes.created, vec![edge_file_name(&conditional)]);
+    assert_eq!(find_edge_files(base_path, &version).unwrap().len(), 2);
+
+    let unblock = Unblock {
+      risk_name: Some("SomeRisk".to_string()),
+      ..Default::default()
+    };
+    let changes = unblock_edge(base_path, version.clone(), &unblock).unwrap();
+    assert_eq!(changes.removed, vec![edge_file_name(&conditional)]);
+    assert_eq!(changes.remaining.len(), 1);
+
+    let changes = unblock_edge(base_path, version.clone(), &Unblock::default()).unwrap();
+    assert_eq!(
+      changes.removed,
+      vec![Path::new(BLOCKED_DIR).join("storage-bug.yaml")]
+    );
+    assert_eq!(find_edge_files(base_path, "0.0.1").unwrap().len(), 1);
+  }
+
+  #[test]
+  fn keep_conditional_file_with_conventional_name() {
+    let tmpdir = tempdir().unwrap();
+    let base_path = Path::new(tmpdir.path());
+    std::fs::create_dir(base_path.join(BLOCKED_DIR)).unwrap();
+    let version = "0.0.0".to_string();
+    let conditional = BlockedEdge::new(&version, ALL_VERSIONS_REGEXP, Some(&risk()));
+    let contents = serde_yaml::to_string(&conditional).unwrap();
+    let conventional_path = base_path.join(edge_file(&version));
+    fs::write(&conventional_path, &contents).unwrap();
+
+    // `{version}.yaml` declares a risk, the unconditional block goes to another file
+    let unconditional = BlockedEdge::new(&version, "4\\.5\\..*", None);
+    let changes = block_edge(base_path, &unconditional).unwrap();
+    let unique_path = Path::new(BLOCKED_DIR).join("0.0.0-2.yaml");
+    assert_eq!(changes.created, vec![unique_path.clone()]);
+    assert!(changes.modified.is_empty());
+    assert_eq!(fs::read_to_string(&conventional_path).unwrap(), contents);
+    let written = fs::read_to_string(base_path.join(&unique_path)).unwrap();
+    assert_eq!(
+      serde_yaml::from_str::<BlockedEdge>(&written).unwrap(),
+      unconditional
+    );
+
+    // Blocking again updates the unconditional file
+    let wider = BlockedEdge::new(&version, "4\\.4\\..*", None);
+    let changes = block_edge(base_path, &wider).unwrap();
+    assert_eq!(changes.modified, vec![unique_path]);
+    assert_eq!(fs::read_to_string(&conventional_path).unwrap(), contents);
+    assert_eq!(find_edge_files(base_path, &version).unwrap().len(), 2);
+  }
+
+  #[test]
+  fn releases_from_channels() {
+    let tmpdir = tempdir().unwrap();
+    let base_path = Path::new(tmpdir.path());
+    std::fs::create_dir(base_path.join(CHANNELS_DIR)).unwrap();
+    fs::write(
+      base_path.join(CHANNELS_DIR).join("stable-4.6.yaml"),
+      "name: stable-4.6\nversions:\n- 4.5.16\n- 4.6.1\n- 4.6.4\n",
+    )
+    .unwrap();
+    fs::write(
+      base_path.join(CHANNELS_DIR).join("fast-4.6.yaml"),
+      "name: fast-4.6\nversions:\n- 4.6.1\n- 4.6.5\n- 4.6.10\n",
+    )
+    .unwrap();
+
+    let range = ReleaseSelector::range(">=4.6.1, <4.6.5").unwrap();
+    assert_eq!(
+      matching_releases(base_path, &range).unwrap(),
+      vec!["4.6.1", "4.6.4"]
+    );
+    let regex = ReleaseSelector::regex("4\\.6\\.1.*").unwrap();
+    assert_eq!(
+      matching_releases(base_path, &regex).unwrap(),
+      vec!["4.6.1", "4.6.10"]
+    );
+    assert!(ReleaseSelector::range(">=foo").is_err());
+    assert!(ReleaseSelector::regex("4.6.(").is_err());
+  }
+
+  #[test]
+  fn channel_membership() {
+    let tmpdir = tempdir().unwrap();
+    let base_path = Path::new(tmpdir.path());
+    std::fs::create_dir(base_path.join(CHANNELS_DIR)).unwrap();
+    let channel_path = base_path.join(channel_file("fast-4.6"));
+    fs::write(
+      &channel_path,
+      "feeder:\n  delay: PT48H\n  name: candidate-4.6\nname: fast-4.6\nversions:\n  - 4.6.10\n  - 4.6.1\n  - 4.6.1\n\n# upgrade targets only\nextra: true\n",
+    )
+    .unwrap();
+
+    let changes = add_to_channel(base_path, "fast-4.6", "4.6.3").unwrap();
+    assert_eq!(changes.modified, vec![channel_file("fast-4.6")]);
+    assert_eq!(
+      fs::read_to_string(&channel_path).unwrap(),
+      "feeder:\n  delay: PT48H\n  name: candidate-4.6\nname: fast-4.6\nversions:\n  - 4.6.1\n  - 4.6.3\n  - 4.6.10\n\n# upgrade targets only\nextra: true\n"
+    );
+    assert!(add_to_channel(base_path, "fast-4.6", "4.6.3")
+      .unwrap()
+      .is_empty());
+
+    let changes = remove_from_channel(base_path, "fast-4.6", "4.6.1").unwrap();
+    assert_eq!(changes.modified, vec![channel_file("fast-4.6")]);
+    assert!(fs::read_to_string(&channel_path)
+      .unwrap()
+      .contains("versions:\n  - 4.6.3\n  - 4.6.10\n"));
+    assert!(remove_from_channel(base_path, "fast-4.6", "4.6.1")
+      .unwrap()
+      .is_empty());
+
+    assert!(add_to_channel(base_path, "stable-4.6", "4.6.3").is_err());
+    assert!(add_to_channel(base_path, "../blocked-edges/x", "4.6.3").is_err());
+  }
+
+  #[test]
+  fn channel_versions_sorted() {
+    let mut versions: Vec<String> = vec!["4.6.10", "not-semver", "4.6.1", "4.6.0-rc.1", "4.6.1"]
+      .into_iter()
+      .map(String::from)
+      .collect();
+    sort_versions(&mut versions);
+    assert_eq!(
+      versions,
+      vec!["4.6.0-rc.1", "4.6.1", "4.6.10", "not-semver"]
+    );
+  }
+
+  #[test]
+  fn validate_graph_data() {
+    let tmpdir = tempdir().unwrap();
+    let base_path = Path::new(tmpdir.path());
+    std::fs::create_dir(base_path.join(CHANNELS_DIR)).unwrap();
+    std::fs::create_dir(base_path.join(BLOCKED_DIR)).unwrap();
+    fs::write(
+      base_path.join(CHANNELS_DIR).join("fast-4.6.yaml"),
+      "name: fast-4.6\nversions:\n- 4.6.1\n- 4.6.3\n",
+    )
+    .unwrap();
+    block_edge(
+      base_path,
+      &BlockedEdge::new("4.6.3", ALL_VERSIONS_REGEXP, None),
+    )
+    .unwrap();
+    assert!(validate_tree(base_path).unwrap().is_empty());
+
+    fs::write(
+      base_path.join(CHANNELS_DIR).join("broken.yaml"),
+      "versions: 4.6.1\n",
+    )
+    .unwrap();
+    // Unconditional files don't duplicate each other
+    fs::write(
+      base_path.join(BLOCKED_DIR).join("4.6.3-copy.yaml"),
+      "to: 4.6.3\nfrom: .*\n",
+    )
+    .unwrap();
+    let conditional =
+      serde_yaml::to_string(&BlockedEdge::new("4.6.3", "4\\.6\\..*", Some(&risk()))).unwrap();
+    for name in &["4.6.3-some-risk.yaml", "4.6.3-some-risk-copy.yaml"] {
+      fs::write(base_path.join(BLOCKED_DIR).join(name), &conditional).unwrap();
+    }
+    fs::write(
+      base_path.join(BLOCKED_DIR).join("4.6.9.yaml"),
+      "to: 4.6.9\nfrom: 4.6.(\n",
+    )
+    .unwrap();
+    let violations = validate_tree(base_path).unwrap();
+    let messages: Vec<String> = violations.iter().map(|v| v.to_string()).collect();
+    assert_eq!(violations.len(), 4, "{:?}", messages);
+    assert_eq!(violations[0].kind, ViolationKind::InvalidChannel);
+    assert!(messages[0].starts_with("channels/broken.yaml: not a valid channel"));
+    assert_eq!(violations[1].kind, ViolationKind::InvalidBlockedEdge);
+    assert!(
+      messages[1].starts_with("blocked-edges/4.6.9.yaml: from \"4.6.(\" is not a valid regexp")
+    );
+    assert_eq!(
+      messages[2],
+      "blocked-edges/4.6.9.yaml: 4.6.9 is not listed in any channel"
+    );
+    assert_eq!(
+      messages[3],
+      "blocked-edges/4.6.3-some-risk-copy.yaml, blocked-edges/4.6.3-some-risk.yaml all block 4.6.3 \
+       for SomeRisk"
+    );
+
+    // Another file for the duplicated risk is still the same problem
+    fs::write(
+      base_path.join(BLOCKED_DIR).join("4.6.3-some-risk-2.yaml"),
+      &conditional,
+    )
+    .unwrap();
+    let after = validate_tree(base_path).unwrap();
+    assert_eq!(after[3].files.len(), 3);
+    assert!(after
+      .iter()
+      .all(|a| violations.iter().any(|b| b.same_problem(a))));
+    assert!(!violations[1].same_problem(&violations[2]));
+  }
+
+  #[test]
+  fn file_names() {
+    assert_eq!(kebab_case("OVNBug"), "ovn-bug");
+    assert_eq!(kebab_case("AWSOldBootImages"), "aws-old-boot-images");
+    assert_eq!(kebab_case("Upgrade4x"), "upgrade4x");
+    assert!(is_conventional_file_name("4.6.3.yaml", "4.6.3"));
+    assert!(is_conventional_file_name("4.6.3-ovn-bug.yaml", "4.6.3"));
+    assert!(!is_conventional_file_name("4.6.30.yaml", "4.6.3"));
+  }
+
+  #[test]
+  fn split_regexp_alternatives() {
+    assert_eq!(split_alternatives(".*"), vec![".*"]);
+    assert_eq!(
+      split_alternatives("4\\.4\\.1|4\\.(5|6)\\..*|[|]"),
+      vec!["4\\.4\\.1", "4\\.(5|6)\\..*", "[|]"]
+    );
+  }
+
+  fn risk() -> Risk {
+    Risk {
+      name: "SomeRisk".to_string(),
+      url: "https://bugzilla.redhat.com/show_bug.cgi?id=1".to_string(),
+      message: "Clusters on AWS may fail to update.".to_string(),
+      matching_rules: vec![MatchingRule::PromQL {
+        promql: PromQLQuery {
+          promql: "cluster_infrastructure_provider{type=\"AWS\"}".to_string(),
+        },
+      }],
+    }
+  }
+
+  #[test]
+  fn blocks_version_by_to() {
+    assert!(blocks_version("to: 4.6.3\nfrom: .*\n", "4.6.3"));
+    assert!(!blocks_version("to: 4.6.30\nfrom: .*\n", "4.6.3"));
+    assert!(blocks_version("not a blocked edge", "4.6.3"));
+  }
+
+  #[test]
+  fn contains_edge_all_versions() {
+    let edge = BlockedEdge::new("0.0.0", ALL_VERSIONS_REGEXP, None);
+    assert!(contains_edge("to: 0.0.0\nfrom: .*\n", &edge));
+    assert!(!contains_edge("to: 0.0.0\nfrom: 4\\.5\\..*\n", &edge));
+    assert!(!contains_edge("to: 0.0.1\nfrom: .*\n", &edge));
+    assert!(!contains_edge("Hello, world!", &edge));
+  }
+
+  #[test]
+  fn block_edge_conditional() {
+    let tmpdir = tempdir().unwrap();
+    let base_path = Path::new(tmpdir.path());
+    std::fs::create_dir(base_path.join(BLOCKED_DIR)).unwrap();
+    let version = "0.0.0".to_string();
+
+    let edge = BlockedEdge::new(&version, "0\\.0\\..*", Some(&risk()));
+    block_edge(base_path, &edge).unwrap();
+
+    let expected_path = base_path.join(BLOCKED_DIR).join("0.0.0-some-risk.yaml");
+    let content = fs::read_to_string(expected_path).unwrap();
+    let value: serde_yaml::Value = serde_yaml::from_str(&content).unwrap();
+    assert_eq!(value["name"].as_str(), Some("SomeRisk"));
+    assert_eq!(value["matchingRules"][0]["type"].as_str(), Some("PromQL"));
+    assert_eq!(
+      value["matchingRules"][0]["promql"]["promql"].as_str(),
+      Some("cluster_infrastructure_provider{type=\"AWS\"}")
+    );
+    let parsed: BlockedEdge = serde_yaml::from_str(&content).unwrap();
+    assert_eq!(parsed.risk(), Some(risk()));
+  }
+
+  #[test]
+  fn validate_edge() {
+    let mut edge = BlockedEdge::new("0.0.0", ALL_VERSIONS_REGEXP, Some(&risk()));
+    assert!(edge.validate().is_empty());
+
+    edge.name = Some("not camel case".to_string());
+    edge.url = Some("bugzilla".to_string());
+    edge.matching_rules = Some(vec![]);
+    assert_eq!(edge.validate().len(), 3);
+
+    edge.message = None;
+    assert_eq!(edge.validate().len(), 1);
+
+    let edge = BlockedEdge::new("0.0.0", "4.5.(", None);
+    assert_eq!(edge.validate().len(), 1);
+    let edge = BlockedEdge::new("../../etc/passwd", ALL_VERSIONS_REGEXP, None);
+    assert_eq!(edge.validate().len(), 1);
+    assert!(BlockedEdge::new("0.0.0", ALL_VERSIONS_REGEXP, None)
+      .validate()
+      .is_empty());
+  }
+
+  #[test]
+  fn block_edge_already_blocked() {
+    let tmpdir = tempdir().unwrap();
+    let base_path = Path::new(tmpdir.path());
+    std::fs::create_dir(base_path.join(BLOCKED_DIR)).unwrap();
+    let version = "0.0.0".to_string();
+
+    block_edge(
+      base_path,
+      &BlockedEdge::new(&version, ALL_VERSIONS_REGEXP, None),
+    )
+    .unwrap();
+    let result = block_edge(
+      base_path,
+      &BlockedEdge::new(&version, ALL_VERSIONS_REGEXP, None),
+    );
+    assert!(result.unwrap().is_empty());
+  }
 }
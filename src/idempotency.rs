@@ -0,0 +1,193 @@
+//! Idempotency-Key handling, so client retries don't perform an action twice
+
+use crate::action::ActionResult;
+use crate::errors::AppError;
+
+use actix_web::HttpRequest;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Header used by clients to mark retries of the same request
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+/// Header set on responses replayed from an earlier request
+pub const REPLAYED_HEADER: &str = "idempotent-replayed";
+const MAX_KEY_LENGTH: usize = 255;
+/// How long results are replayed for retried requests
+const RESULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug)]
+enum State {
+  InProgress,
+  Done(Box<ActionResult>),
+}
+
+#[derive(Debug)]
+struct Entry {
+  fingerprint: String,
+  state: State,
+  created: Instant,
+}
+
+/// Results of actions performed with an idempotency key
+#[derive(Debug, Default)]
+pub struct Cache {
+  entries: Mutex<HashMap<String, Entry>>,
+}
+
+/// Outcome of registering a request with an idempotency key
+#[derive(Debug)]
+pub enum Begin<'a> {
+  /// First request with this key, the action should be performed
+  Started(Pending<'a>),
+  /// Result of the earlier request with this key
+  Replay(ActionResult),
+}
+
+/// Action in progress, the key is released if it's dropped without a result
+#[derive(Debug)]
+pub struct Pending<'a> {
+  cache: &'a Cache,
+  key: String,
+  finished: bool,
+}
+
+impl<'a> Pending<'a> {
+  /// Store the result, failed actions release the key so they can be retried
+  pub fn finish(mut self, result: Option<&ActionResult>) {
+    self.finished = true;
+    let mut entries = self.cache.entries.lock().unwrap();
+    match result {
+      Some(result) => {
+        if let Some(entry) = entries.get_mut(&self.key) {
+          entry.state = State::Done(Box::new(result.clone()));
+        }
+      }
+      None => {
+        entries.remove(&self.key);
+      }
+    }
+  }
+}
+
+impl<'a> Drop for Pending<'a> {
+  fn drop(&mut self) {
+    if !self.finished {
+      self.cache.entries.lock().unwrap().remove(&self.key);
+    }
+  }
+}
+
+impl Cache {
+  /// Register a request with `key`. `fingerprint` identifies the action,
+  /// so a key reused for a different action is rejected.
+  pub fn begin(&self, key: &str, fingerprint: &str) -> Result<Begin<'_>, AppError> {
+    let mut entries = self.entries.lock().unwrap();
+    entries.retain(|_, entry| entry.created.elapsed() < RESULT_TTL);
+    match entries.get(key) {
+      Some(entry) if entry.fingerprint != fingerprint => Err(AppError::IdempotencyConflict(
+        "key was already used for a different action".to_string(),
+      )),
+      Some(Entry {
+        state: State::InProgress,
+        ..
+      }) => Err(AppError::IdempotencyConflict(
+        "request with this key is still in progress".to_string(),
+      )),
+      Some(Entry {
+        state: State::Done(result),
+        ..
+      }) => Ok(Begin::Replay(result.as_ref().clone())),
+      None => {
+        entries.insert(
+          key.to_string(),
+          Entry {
+            fingerprint: fingerprint.to_string(),
+            state: State::InProgress,
+            created: Instant::now(),
+          },
+        );
+        Ok(Begin::Started(Pending {
+          cache: self,
+          key: key.to_string(),
+          finished: false,
+        }))
+      }
+    }
+  }
+}
+
+/// Read Idempotency-Key header, rejecting malformed keys
+pub fn key_from_request(req: &HttpRequest) -> Result<Option<String>, AppError> {
+  let value = match req.headers().get(IDEMPOTENCY_KEY_HEADER) {
+    Some(value) => value,
+    None => return Ok(None),
+  };
+  let key = value
+    .to_str()
+    .map_err(|_| AppError::InvalidAction("Idempotency-Key is not valid ASCII".to_string()))?;
+  if key.is_empty() || key.len() > MAX_KEY_LENGTH || !key.chars().all(|c| c.is_ascii_graphic()) {
+    return Err(AppError::InvalidAction(format!(
+      "Idempotency-Key must be 1-{} printable characters",
+      MAX_KEY_LENGTH
+    )));
+  }
+  Ok(Some(key.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn created() -> ActionResult {
+    ActionResult::Created("https://github.com/org/repo/pull/1".to_string())
+  }
+
+  #[test]
+  fn replay_finished_result() {
+    let cache = Cache::default();
+    match cache.begin("key", "default:disable:4.5.1").unwrap() {
+      Begin::Started(pending) => pending.finish(Some(&created())),
+      Begin::Replay(_) => panic!("unexpected replay"),
+    }
+    match cache.begin("key", "default:disable:4.5.1").unwrap() {
+      Begin::Replay(result) => assert_eq!(result, created()),
+      Begin::Started(_) => panic!("action started twice"),
+    };
+  }
+
+  #[test]
+  fn reject_in_progress_and_mismatch() {
+    let cache = Cache::default();
+    let _pending = cache.begin("key", "default:disable:4.5.1").unwrap();
+    assert_eq!(
+      cache
+        .begin("key", "default:disable:4.5.1")
+        .unwrap_err()
+        .kind(),
+      "idempotency_conflict"
+    );
+    assert_eq!(
+      cache
+        .begin("key", "default:enable:4.5.1")
+        .unwrap_err()
+        .kind(),
+      "idempotency_conflict"
+    );
+  }
+
+  #[test]
+  fn release_key_on_failure() {
+    let cache = Cache::default();
+    match cache.begin("key", "default:disable:4.5.1").unwrap() {
+      Begin::Started(pending) => pending.finish(None),
+      Begin::Replay(_) => panic!("unexpected replay"),
+    }
+    // Dropped without a result, e.g. client disconnected
+    drop(cache.begin("key", "default:disable:4.5.1").unwrap());
+    assert!(matches!(
+      cache.begin("key", "default:disable:4.5.1").unwrap(),
+      Begin::Started(_)
+    ));
+  }
+}
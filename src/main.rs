@@ -24,80 +24,330 @@ extern crate serde_yaml;
 use anyhow::Context;
 
 use actix_web::dev::ServiceRequest;
-use actix_web::http::header::CONTENT_TYPE;
-use actix_web::{guard, middleware, web, App, HttpResponse, HttpServer};
+use actix_web::http::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use actix_web::{guard, middleware, web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer};
 use actix_web_httpauth::extractors::bearer::BearerAuth;
 use actix_web_httpauth::middleware::HttpAuthentication;
 use actix_web_prom::PrometheusMetrics;
+use structopt::StructOpt;
+use tracing_futures::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 
 pub mod action;
 pub mod config;
 pub mod errors;
 pub mod git_repo;
 pub mod github;
+pub mod graph;
 pub mod graph_schema;
+pub mod idempotency;
+pub mod metrics;
+pub mod readiness;
+pub mod reload;
+pub mod request_id;
+pub mod telemetry;
+pub mod tls;
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
-    let settings = config::AppSettings::assemble()
-        .context("could not assemble AppSettings")
-        .unwrap();
-    std::env::set_var("RUST_LOG", "actix_web=debug");
-    env_logger::Builder::from_default_env()
-        .filter(Some(module_path!()), settings.verbosity)
-        .init();
+    let cli_opts = config::CliOptions::from_args();
+    let command = cli_opts.command.clone();
+    let settings = match config::AppSettings::assemble_from(cli_opts.clone()) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("could not assemble AppSettings: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = action::check_commit_signing(&settings) {
+        eprintln!("could not set up commit signing: {:#}", e);
+        std::process::exit(1);
+    }
+    if let Some(config::Command::CheckConfig { probe_github }) = command {
+        std::process::exit(check_config(&settings, probe_github).await);
+    }
+
+    let _telemetry = telemetry::init(settings.verbosity, &settings.telemetry)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{:#}", e)))?;
 
     let service_addr = (settings.service.address, settings.service.port);
-    let data = web::Data::new(settings);
-    let prometheus = PrometheusMetrics::new("graph_breaker", Some("/metrics"), None);
+    let tls_config = match &settings.service.tls {
+        Some(tls_settings) => Some(
+            tls::server_config(tls_settings)
+                .context("could not load TLS configuration")
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{:#}", e)))?,
+        ),
+        None => None,
+    };
+    let client_certificate_required = settings.service.requires_client_certificate();
+    let readiness_interval =
+        std::time::Duration::from_secs(settings.service.readiness_interval_secs);
+    let data = web::Data::new(config::SharedSettings::new(settings));
+    reload::spawn_reloader(data.clone(), cli_opts);
 
-    HttpServer::new(move || {
-        let auth = HttpAuthentication::bearer(bearer_validator);
+    let readiness_report = readiness::SharedReport::default();
+    readiness::spawn_checks(data.clone(), readiness_report.clone(), readiness_interval);
+    let readiness_data = web::Data::new(readiness_report);
+    let idempotency_data = web::Data::new(idempotency::Cache::default());
+    let registry = metrics::registry()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+    let prometheus =
+        PrometheusMetrics::new_with_registry(registry, "graph_breaker", Some("/metrics"), None)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+
+    let app = move || {
         App::new()
             .app_data(data.clone())
+            .app_data(readiness_data.clone())
+            .app_data(idempotency_data.clone())
+            .wrap_fn(request_id::wrap)
             .wrap(prometheus.clone())
-            .wrap(middleware::Logger::default().exclude("/healthz"))
+            .wrap(
+                middleware::Logger::default()
+                    .exclude("/healthz")
+                    .exclude("/readyz"),
+            )
             .data(web::JsonConfig::default().limit(4096))
             .service(web::resource("/healthz").to(health))
+            .service(web::resource("/readyz").to(ready))
             .service(
                 web::resource("/action")
-                    .wrap(auth)
+                    .wrap(HttpAuthentication::bearer(bearer_validator))
                     .guard(guard::Header(CONTENT_TYPE.as_str(), "application/json"))
                     .route(web::post().to(action)),
             )
-    })
-    .bind(service_addr)?
-    .run()
+            .service(
+                web::resource("/targets/{name}/action")
+                    .wrap(HttpAuthentication::bearer(bearer_validator))
+                    .guard(guard::Header(CONTENT_TYPE.as_str(), "application/json"))
+                    .route(web::post().to(target_action)),
+            )
+    };
+    match tls_config {
+        Some(cfg) => tls::listen(
+            actix_server::Server::build(),
+            std::net::TcpListener::bind(service_addr)?,
+            cfg,
+            client_certificate_required,
+            app,
+        )?
+        .run(),
+        None => HttpServer::new(app).bind(service_addr)?.run(),
+    }
     .await
 }
 
+/// Print effective config and optionally probe GitHub access, returning exit code
+async fn check_config(settings: &config::AppSettings, probe_github: bool) -> i32 {
+    for (key, value) in settings.entries(true) {
+        println!("{} = {}", key, value);
+    }
+    if probe_github {
+        let report = readiness::run_checks(settings).await;
+        for check in &report.checks {
+            let status = if check.ok { "ok" } else { "FAILED" };
+            println!("{} {}: {}", status, check.name, check.message);
+        }
+        if !report.ready {
+            eprintln!("GitHub access checks failed");
+            return 1;
+        }
+    }
+    println!("Configuration is valid");
+    0
+}
+
 /// Health endpoint
 fn health() -> HttpResponse {
     HttpResponse::Ok().finish()
 }
 
+/// Readiness endpoint, reports results of the latest GitHub and git checks
+fn ready(report: web::Data<readiness::SharedReport>) -> HttpResponse {
+    let report = report.read().unwrap().clone();
+    if report.ready {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::ServiceUnavailable().json(report)
+    }
+}
+
 /// Check "Authorization" header has expected token
 async fn bearer_validator(
     req: ServiceRequest,
-    _credentials: BearerAuth,
+    credentials: BearerAuth,
 ) -> Result<ServiceRequest, actix_web::Error> {
-    let settings = req.app_data::<config::AppSettings>().unwrap();
-    if _credentials.token() == settings.service.client_auth_token {
-        Ok(req)
+    let settings = req
+        .app_data::<config::SharedSettings>()
+        .unwrap()
+        .current();
+    let certificate = req
+        .extensions()
+        .get::<tls::ClientCertificate>()
+        .cloned()
+        .unwrap_or_default();
+    if certificate.required && certificate.common_name.is_none() {
+        log::warn!("Rejected request without client certificate");
+        return Err(errors::AppError::InvalidAuthenticationToken().into());
+    }
+    let certificate_client = certificate
+        .common_name
+        .as_deref()
+        .and_then(|cn| settings.service.client_by_common_name(cn));
+
+    let expected_token = settings.service.read_client_auth_token().map_err(|e| {
+        log::warn!("Failed to read client auth token: {:#}", e);
+        errors::AppError::InvalidAuthenticationToken()
+    })?;
+    let token_client = if credentials.token() == expected_token {
+        None
     } else {
-        Err(errors::AppError::InvalidAuthenticationToken().into())
+        let client = settings
+            .service
+            .client_by_token(credentials.token())
+            .map_err(|e| {
+                log::warn!("Failed to read client auth token: {:#}", e);
+                errors::AppError::InvalidAuthenticationToken()
+            })?;
+        match client {
+            Some(identity) => Some(identity),
+            None => return Err(errors::AppError::InvalidAuthenticationToken().into()),
+        }
+    };
+
+    // A client token presented with another client's certificate is rejected
+    let client = match (token_client, certificate_client) {
+        (Some(token), Some(cert)) if token != cert => {
+            log::warn!(
+                "Rejected token of client {} presented with certificate of client {}",
+                token.id,
+                cert.id
+            );
+            return Err(errors::AppError::InvalidAuthenticationToken().into());
+        }
+        (token, cert) => token.or(cert),
+    };
+    if let Some(identity) = client {
+        req.extensions_mut().insert(identity);
     }
+    Ok(req)
 }
 
+/// Perform action on the target set in the action, or the default one
 async fn action(
-    settings: web::Data<config::AppSettings>,
+    req: HttpRequest,
+    settings: web::Data<config::SharedSettings>,
+    cache: web::Data<idempotency::Cache>,
+    item: web::Json<action::Action>,
+) -> Result<HttpResponse, errors::AppError> {
+    let action = item.into_inner();
+    let target = action.target().map(|t| t.to_string());
+    run_action(req, settings, cache, action, target).await
+}
+
+/// Perform action on the target set in the URL path
+async fn target_action(
+    req: HttpRequest,
+    settings: web::Data<config::SharedSettings>,
+    cache: web::Data<idempotency::Cache>,
+    path: web::Path<String>,
     item: web::Json<action::Action>,
 ) -> Result<HttpResponse, errors::AppError> {
+    let action = item.into_inner();
+    let target = path.into_inner();
+    if let Some(action_target) = action.target() {
+        if action_target != target {
+            return Err(errors::AppError::InvalidAction(format!(
+                "action target {} doesn't match target {} in path",
+                action_target, target
+            )));
+        }
+    }
+    run_action(req, settings, cache, action, Some(target)).await
+}
+
+async fn run_action(
+    req: HttpRequest,
+    settings: web::Data<config::SharedSettings>,
+    cache: web::Data<idempotency::Cache>,
+    mut action: action::Action,
+    target: Option<String>,
+) -> Result<HttpResponse, errors::AppError> {
+    action.normalize().map_err(errors::AppError::InvalidAction)?;
+    action.validate().map_err(errors::AppError::InvalidAction)?;
+    let target_name = target.unwrap_or_else(|| config::DEFAULT_TARGET.to_string());
+    let github_settings = settings
+        .current()
+        .target(Some(&target_name))
+        .map_err(|e| errors::AppError::InvalidAction(e.to_string()))?
+        .clone();
+
+    let client = req.extensions().get::<config::ClientIdentity>().cloned();
+
+    // Replay results of retried requests, keys are scoped per client
+    let pending = match idempotency::key_from_request(&req)? {
+        Some(key) => {
+            let client_id = client.as_ref().map(|c| c.id.as_str()).unwrap_or_default();
+            let scoped_key = format!("{}/{}", client_id, key);
+            match cache.begin(&scoped_key, &action.fingerprint(&target_name))? {
+                idempotency::Begin::Started(pending) => Some(pending),
+                idempotency::Begin::Replay(result) => {
+                    let mut response = action_response(&result);
+                    response.headers_mut().insert(
+                        HeaderName::from_static(idempotency::REPLAYED_HEADER),
+                        HeaderValue::from_static("true"),
+                    );
+                    return Ok(response);
+                }
+            }
+        }
+        None => None,
+    };
+
+    // Continue trace started by the caller
+    let span = tracing::warn_span!("action_request");
+    span.set_parent(telemetry::parent_context(req.headers()));
+
     // Perform action
-    let result = action::perform_action(item.into_inner(), settings.github.clone())
-        .await
-        .map_err(|msg| errors::AppError::ActionFailed(msg.to_string()))?;
-    Ok(HttpResponse::from(result))
+    let result = action::perform_action(action, &target_name, github_settings, client)
+        .instrument(span)
+        .await;
+    if let Some(pending) = pending {
+        pending.finish(result.as_ref().ok());
+    }
+    let result = result.map_err(|e| {
+        e.downcast::<errors::AppError>()
+            .unwrap_or_else(|msg| errors::AppError::ActionFailed(msg.to_string()))
+    })?;
+    Ok(action_response(&result))
+}
+
+/// Build HTTP response for the action result
+fn action_response(result: &action::ActionResult) -> HttpResponse {
+    match result {
+        action::ActionResult::AlreadySatisfied { files } => HttpResponse::Ok().json(json!({
+            "status": "already_satisfied",
+            "files": files,
+        })),
+        action::ActionResult::DryRun {
+            created,
+            modified,
+            removed,
+            impact,
+            unsafe_reason,
+        } => HttpResponse::Ok().json(json!({
+            "status": "dry_run",
+            "created": created,
+            "modified": modified,
+            "removed": removed,
+            "impact": impact,
+            "unsafe_reason": unsafe_reason,
+        })),
+        action::ActionResult::NothingToDo => {
+            HttpResponse::Ok().body("nothing to do, graph data already up to date")
+        }
+        _ => HttpResponse::from(result.url().unwrap_or_default().to_string()),
+    }
 }
 
 #[cfg(test)]
@@ -111,7 +361,7 @@ mod tests {
         ($settings:expr) => {
             test::init_service(
                 App::new()
-                    .app_data(web::Data::new($settings))
+                    .app_data(web::Data::new(config::SharedSettings::new($settings)))
                     .wrap(HttpAuthentication::bearer(bearer_validator))
                     .service(
                         web::resource("/test")
@@ -123,6 +373,55 @@ mod tests {
         };
     }
 
+    #[actix_rt::test]
+    async fn test_request_id_passed_through() {
+        let mut settings = config::AppSettings::default();
+        settings.service.client_auth_token = "foo".to_string();
+        let mut app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(config::SharedSettings::new(settings)))
+                .wrap(HttpAuthentication::bearer(bearer_validator))
+                .wrap_fn(request_id::wrap)
+                .service(web::resource("/test").to(|| async { HttpResponse::Ok() })),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri("/test")
+            .header(request_id::REQUEST_ID_HEADER, "abc-123")
+            .header(AUTHORIZATION, "Bearer bar")
+            .to_request();
+        let err = app.call(req).await.unwrap_err();
+        let resp = test::TestRequest::default()
+            .to_srv_request()
+            .into_response(err.as_response_error().error_response());
+        assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            resp.headers().get(request_id::REQUEST_ID_HEADER).unwrap(),
+            "abc-123"
+        );
+        let body: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(body["request_id"], "abc-123");
+    }
+
+    #[actix_rt::test]
+    async fn test_request_id_generated() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap_fn(request_id::wrap)
+                .service(web::resource("/test").to(|| async { HttpResponse::Ok() })),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri("/test")
+            .header(request_id::REQUEST_ID_HEADER, "not valid")
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let request_id = resp.headers().get(request_id::REQUEST_ID_HEADER).unwrap();
+        assert_ne!(request_id, "not valid");
+        assert!(!request_id.is_empty());
+    }
+
     #[actix_rt::test]
     async fn test_missing_bearer_auth() {
         let mut settings = config::AppSettings::default();
@@ -170,4 +469,98 @@ mod tests {
             http::StatusCode::UNAUTHORIZED
         );
     }
+
+    macro_rules! client_app {
+        ($settings:expr) => {
+            test::init_service(
+                App::new()
+                    .app_data(web::Data::new(config::SharedSettings::new($settings)))
+                    .wrap(HttpAuthentication::bearer(bearer_validator))
+                    .service(web::resource("/test").to(|req: HttpRequest| async move {
+                        let client = req.extensions().get::<config::ClientIdentity>().cloned();
+                        HttpResponse::Ok().body(client.map(|c| c.id).unwrap_or_default())
+                    })),
+            )
+            .await
+        };
+    }
+
+    fn client_settings() -> config::AppSettings {
+        let mut settings = config::AppSettings::default();
+        settings.service.client_auth_token = "foo".to_string();
+        for (id, token) in &[("monitor", "baz"), ("other", "qux")] {
+            settings.service.clients.insert(
+                id.to_string(),
+                config::ClientSettings {
+                    token: token.to_string(),
+                    name: id.to_string(),
+                    email: format!("{}@example.com", id),
+                    common_name: Some(format!("{}-cn", id)),
+                    ..Default::default()
+                },
+            );
+        }
+        settings
+    }
+
+    macro_rules! request_with_certificate {
+        ($token:expr, $common_name:expr) => {{
+            let req = test::TestRequest::with_uri("/test")
+                .header(AUTHORIZATION, format!("Bearer {}", $token))
+                .to_request();
+            req.extensions_mut().insert(tls::ClientCertificate {
+                required: true,
+                common_name: $common_name.map(|cn: &str| cn.to_string()),
+            });
+            req
+        }};
+    }
+
+    #[actix_rt::test]
+    async fn test_client_certificate_required() {
+        let mut app = client_app!(client_settings());
+        let resp = app.call(request_with_certificate!("foo", None)).await;
+        assert_eq!(
+            resp.unwrap_err().as_response_error().status_code(),
+            http::StatusCode::UNAUTHORIZED
+        );
+
+        // Plain HTTP connections and probes don't need a certificate
+        let req = test::TestRequest::with_uri("/test")
+            .header(AUTHORIZATION, "Bearer foo")
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_client_certificate_identity() {
+        let mut app = client_app!(client_settings());
+        let resp = app
+            .call(request_with_certificate!("foo", Some("monitor-cn")))
+            .await
+            .unwrap();
+        assert_eq!(test::read_body(resp).await, "monitor");
+
+        let resp = app
+            .call(request_with_certificate!("baz", Some("monitor-cn")))
+            .await
+            .unwrap();
+        assert_eq!(test::read_body(resp).await, "monitor");
+
+        // Certificates of unknown clients are verified by TLS, the token sets the identity
+        let resp = app
+            .call(request_with_certificate!("qux", Some("unknown-cn")))
+            .await
+            .unwrap();
+        assert_eq!(test::read_body(resp).await, "other");
+
+        let resp = app
+            .call(request_with_certificate!("qux", Some("monitor-cn")))
+            .await;
+        assert_eq!(
+            resp.unwrap_err().as_response_error().status_code(),
+            http::StatusCode::UNAUTHORIZED
+        );
+    }
 }
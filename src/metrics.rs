@@ -0,0 +1,54 @@
+//! Action-level Prometheus metrics
+
+use lazy_static::lazy_static;
+use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry};
+
+const NAMESPACE: &str = "graph_breaker";
+
+lazy_static! {
+  /// Performed actions by target, type and outcome
+  pub static ref ACTIONS_TOTAL: IntCounterVec = IntCounterVec::new(
+    Opts::new("actions_total", "Performed actions by type and outcome").namespace(NAMESPACE),
+    &["target", "type", "outcome"]
+  )
+  .unwrap();
+
+  /// Durations of clone, fetch, push and PR creation steps
+  pub static ref OPERATION_DURATION: HistogramVec = HistogramVec::new(
+    HistogramOpts::new(
+      "operation_duration_seconds",
+      "Duration of git and GitHub operations"
+    )
+    .namespace(NAMESPACE),
+    &["target", "operation"]
+  )
+  .unwrap();
+
+  /// Blocked edges files in the upstream repo at last fetch or readiness check
+  pub static ref BLOCKED_EDGE_FILES: IntGaugeVec = IntGaugeVec::new(
+    Opts::new("blocked_edge_files", "Blocked edges files in the target repo").namespace(NAMESPACE),
+    &["target"]
+  )
+  .unwrap();
+
+  /// Open pull requests created by graph-breaker at last readiness check
+  pub static ref OPEN_PULL_REQUESTS: IntGaugeVec = IntGaugeVec::new(
+    Opts::new(
+      "open_pull_requests",
+      "Open pull requests from the fork in the target repo"
+    )
+    .namespace(NAMESPACE),
+    &["target"]
+  )
+  .unwrap();
+}
+
+/// Register action metrics in a new registry, which is then exposed on `/metrics`
+pub fn registry() -> Result<Registry, prometheus::Error> {
+  let registry = Registry::new();
+  registry.register(Box::new(ACTIONS_TOTAL.clone()))?;
+  registry.register(Box::new(OPERATION_DURATION.clone()))?;
+  registry.register(Box::new(BLOCKED_EDGE_FILES.clone()))?;
+  registry.register(Box::new(OPEN_PULL_REQUESTS.clone()))?;
+  Ok(registry)
+}
@@ -0,0 +1,220 @@
+//! Readiness checks for GitHub and git access
+
+use crate::{config, git_repo, github, graph_schema, metrics};
+
+use actix_web::web;
+use log::{debug, warn};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Token scopes which allow pushing branches and creating PRs
+const REQUIRED_SCOPES: &[&str] = &["repo", "public_repo"];
+
+/// Result of a single readiness check
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckStatus {
+  pub name: String,
+  pub ok: bool,
+  pub message: String,
+}
+
+impl CheckStatus {
+  fn new(name: &str, result: Result<String, String>) -> Self {
+    let (ok, message) = match result {
+      Ok(msg) => (true, msg),
+      Err(msg) => (false, msg),
+    };
+    CheckStatus {
+      name: name.to_string(),
+      ok,
+      message,
+    }
+  }
+}
+
+/// Latest readiness check results
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReadinessReport {
+  /// All checks passed
+  pub ready: bool,
+  /// Unix timestamp of the last check run
+  pub checked_at: Option<u64>,
+  pub checks: Vec<CheckStatus>,
+}
+
+/// Readiness report shared between the checker and the HTTP handler
+pub type SharedReport = Arc<RwLock<ReadinessReport>>;
+
+/// Periodically run readiness checks and store results in `report`
+pub fn spawn_checks(
+  settings: web::Data<config::SharedSettings>,
+  report: SharedReport,
+  interval: Duration,
+) {
+  actix_rt::spawn(async move {
+    let mut ticker = actix_rt::time::interval(interval);
+    loop {
+      ticker.tick().await;
+      // Pick up reloaded settings
+      let current = settings.current();
+      let result = run_checks(&current).await;
+      if !result.ready {
+        warn!("Readiness checks failed: {:?}", result.checks);
+      }
+      *report.write().unwrap() = result;
+      refresh_metrics(&current).await;
+    }
+  });
+}
+
+/// Refresh blocked edges files and open pull requests gauges of all targets
+async fn refresh_metrics(settings: &config::AppSettings) {
+  for (name, github) in settings.all_targets() {
+    let token = match github.read_token() {
+      Ok(token) => token,
+      Err(e) => {
+        debug!("Skipping metrics of target {}: {:#}", name, e);
+        continue;
+      }
+    };
+    match github::list_directory(
+      &token,
+      &github.target_organization,
+      &github.target_repo,
+      graph_schema::BLOCKED_DIR,
+      &github.base_branch,
+    )
+    .await
+    {
+      Ok(names) => metrics::BLOCKED_EDGE_FILES
+        .with_label_values(&[name])
+        .set(names.iter().filter(|n| n.ends_with(".yaml")).count() as i64),
+      Err(e) => warn!(
+        "Failed to count blocked edges files of target {}: {:#}",
+        name, e
+      ),
+    }
+
+    let mut repo = github::GithubRepo::new(token, &github.target_organization, &github.target_repo);
+    match repo.count_open_prs(&github.fork_organization).await {
+      Ok(count) => metrics::OPEN_PULL_REQUESTS
+        .with_label_values(&[name])
+        .set(count),
+      Err(e) => warn!(
+        "Failed to count open pull requests of target {}: {}",
+        name, e
+      ),
+    }
+  }
+}
+
+/// Run readiness checks for all targets
+pub async fn run_checks(settings: &config::AppSettings) -> ReadinessReport {
+  debug!("Running readiness checks");
+  let mut checks = vec![];
+  for (name, github) in settings.all_targets() {
+    checks.extend(target_checks(name, github).await);
+  }
+  let checked_at = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .ok()
+    .map(|d| d.as_secs());
+  ReadinessReport {
+    ready: checks.iter().all(|c| c.ok),
+    checked_at,
+    checks,
+  }
+}
+
+/// Run readiness checks for a single target, check names are prefixed with target name
+async fn target_checks(target: &str, settings: &config::GithubSettings) -> Vec<CheckStatus> {
+  let token = settings.read_token().unwrap_or_default();
+  let checks = vec![
+    CheckStatus::new("github_token", check_token(settings).await),
+    CheckStatus::new(
+      "target_repo",
+      check_repo_access(
+        &token,
+        &settings.target_organization,
+        &settings.target_repo,
+        false,
+      )
+      .await,
+    ),
+    CheckStatus::new(
+      "fork_repo",
+      check_repo_access(
+        &token,
+        &settings.fork_organization,
+        &settings.fork_repo,
+        true,
+      )
+      .await,
+    ),
+    CheckStatus::new(
+      "target_remote",
+      check_remote(git_repo::upstream_url(
+        &settings.target_organization,
+        &settings.target_repo,
+      ))
+      .await,
+    ),
+    CheckStatus::new(
+      "fork_remote",
+      check_remote(git_repo::fork_url(
+        &settings.fork_organization,
+        &settings.fork_repo,
+      ))
+      .await,
+    ),
+  ];
+  checks
+    .into_iter()
+    .map(|c| CheckStatus {
+      name: format!("{}/{}", target, c.name),
+      ..c
+    })
+    .collect()
+}
+
+async fn check_token(settings: &config::GithubSettings) -> Result<String, String> {
+  let token = settings
+    .read_token()
+    .map_err(|e| format!("token unavailable: {:#}", e))?;
+  let scopes = github::token_scopes(&token)
+    .await
+    .map_err(|e| format!("token rejected: {}", e))?;
+  if scopes.is_empty() {
+    return Ok("token valid, no OAuth scopes reported".to_string());
+  }
+  if !scopes.iter().any(|s| REQUIRED_SCOPES.contains(&s.as_str())) {
+    return Err(format!(
+      "token scopes {:?} don't include any of {:?}",
+      scopes, REQUIRED_SCOPES
+    ));
+  }
+  Ok(format!("token valid, scopes {:?}", scopes))
+}
+
+async fn check_repo_access(
+  token: &str,
+  org: &str,
+  repo: &str,
+  needs_push: bool,
+) -> Result<String, String> {
+  let permissions = github::repo_permissions(token, org, repo)
+    .await
+    .map_err(|e| format!("{}/{} not accessible: {}", org, repo, e))?;
+  if needs_push && !permissions.push {
+    return Err(format!("no push permission on {}/{}", org, repo));
+  }
+  Ok(format!("{}/{} accessible", org, repo))
+}
+
+async fn check_remote(url: String) -> Result<String, String> {
+  let target = url.clone();
+  web::block(move || git_repo::check_remote(&target))
+    .await
+    .map(|_| format!("{} reachable", url))
+    .map_err(|e| format!("{} not reachable: {}", url, e))
+}
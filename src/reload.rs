@@ -0,0 +1,141 @@
+//! Configuration hot reload on config file change or SIGHUP
+
+use crate::{action, config};
+
+use actix_rt::signal::unix::{signal, SignalKind};
+use actix_web::web;
+use log::{debug, error, info, warn};
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// Settings which are only applied on restart
+const RESTART_REQUIRED: &[&str] = &[
+  "verbosity",
+  "service.address",
+  "service.port",
+  "service.tls",
+  "service.readiness_interval_secs",
+  "service.reload_interval_secs",
+  "telemetry",
+];
+
+fn modified(path: &Path) -> Option<SystemTime> {
+  fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Reload settings on SIGHUP and when the config file changes, re-applying the startup CLI options
+pub fn spawn_reloader(settings: web::Data<config::SharedSettings>, cli_opts: config::CliOptions) {
+  let current = settings.current();
+  let interval = Duration::from_secs(current.service.reload_interval_secs);
+
+  if let Some(config_path) = current.config_path.clone() {
+    let watched = settings.clone();
+    let watched_opts = cli_opts.clone();
+    actix_rt::spawn(async move {
+      let mut last_modified = modified(&config_path);
+      let mut ticker = actix_rt::time::interval(interval);
+      loop {
+        ticker.tick().await;
+        let file_modified = modified(&config_path);
+        if file_modified != last_modified {
+          debug!("Config file {} changed", config_path.display());
+          last_modified = file_modified;
+          reload(&watched, &watched_opts);
+        }
+      }
+    });
+  }
+
+  actix_rt::spawn(async move {
+    let mut hangup = match signal(SignalKind::hangup()) {
+      Ok(s) => s,
+      Err(e) => {
+        error!("Failed to install SIGHUP handler: {}", e);
+        return;
+      }
+    };
+    while hangup.recv().await.is_some() {
+      info!("Received SIGHUP, reloading config");
+      reload(&settings, &cli_opts);
+    }
+  });
+}
+
+/// Assemble settings again and swap them in, keeping the old ones if new config is invalid
+pub fn reload(settings: &config::SharedSettings, cli_opts: &config::CliOptions) {
+  let new_settings = match config::AppSettings::assemble_from(cli_opts.clone()) {
+    Ok(s) => s,
+    Err(e) => {
+      error!("Invalid config, keeping previous settings: {:#}", e);
+      return;
+    }
+  };
+  let changes = settings.current().changes(&new_settings);
+  if changes.is_empty() {
+    debug!("Config reloaded, no changes");
+    return;
+  }
+  if let Err(e) = action::check_commit_signing(&new_settings) {
+    error!("Invalid config, keeping previous settings: {:#}", e);
+    return;
+  }
+  settings.replace(new_settings);
+  for change in changes {
+    if RESTART_REQUIRED.iter().any(|key| change.starts_with(key)) {
+      warn!("Config changed, applied on restart only: {}", change);
+    } else {
+      info!("Config changed: {}", change);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use structopt::StructOpt;
+  use tempfile::tempdir;
+
+  const CONFIG: &str = "[service]\nclient_auth_token = \"foo\"\n[github]\ntoken = \"bar\"\n";
+
+  #[test]
+  fn reload_keeps_settings_on_invalid_config() {
+    let tmpdir = tempdir().unwrap();
+    let path = tmpdir.path().join("config.toml");
+    fs::write(&path, CONFIG).unwrap();
+    let cli_opts = config::CliOptions::from_iter(vec![
+      "graph-breaker",
+      "-c",
+      path.to_str().unwrap(),
+      "--fork-organization",
+      "cli-org",
+    ]);
+    let settings =
+      config::SharedSettings::new(config::AppSettings::assemble_from(cli_opts.clone()).unwrap());
+
+    fs::write(&path, format!("{}target_repo = \"other\"\n", CONFIG)).unwrap();
+    reload(&settings, &cli_opts);
+    assert_eq!(settings.current().github.target_repo, "other");
+    assert_eq!(settings.current().github.fork_organization, "cli-org");
+
+    fs::write(&path, "[service]\nport = 0\n").unwrap();
+    reload(&settings, &cli_opts);
+    assert_eq!(settings.current().github.target_repo, "other");
+    assert_eq!(settings.current().service.client_auth_token, "foo");
+
+    fs::write(&path, "not toml").unwrap();
+    reload(&settings, &cli_opts);
+    assert_eq!(settings.current().github.target_repo, "other");
+
+    fs::write(
+      &path,
+      format!(
+        "{}target_repo = \"signed\"\n[github.commit.signing]\nformat = \"gpg\"\nkey = \"ABCD\"\nprogram = \"false\"\n",
+        CONFIG
+      ),
+    )
+    .unwrap();
+    reload(&settings, &cli_opts);
+    assert_eq!(settings.current().github.target_repo, "other");
+  }
+}
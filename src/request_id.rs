@@ -0,0 +1,104 @@
+//! Request correlation IDs
+
+use crate::errors;
+
+use actix_service::Service;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::HeaderMap;
+use actix_web::{Error, HttpMessage};
+use futures::future::Future;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use std::fmt;
+use tracing_futures::Instrument;
+
+/// Header used to pass request ID from clients and back in responses
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const GENERATED_ID_LENGTH: usize = 16;
+const MAX_ID_LENGTH: usize = 128;
+
+/// Request correlation ID, available in request extensions
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+  /// Use ID from X-Request-ID header if it's valid, otherwise generate a new one
+  pub fn from_request(req: &ServiceRequest) -> Self {
+    req
+      .headers()
+      .get(REQUEST_ID_HEADER)
+      .and_then(|value| value.to_str().ok())
+      .filter(|id| is_valid(id))
+      .map(|id| RequestId(id.to_string()))
+      .unwrap_or_else(RequestId::generate)
+  }
+
+  fn generate() -> Self {
+    let id: String = thread_rng()
+      .sample_iter(&Alphanumeric)
+      .take(GENERATED_ID_LENGTH)
+      .collect();
+    RequestId(id)
+  }
+}
+
+impl fmt::Display for RequestId {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+fn is_valid(id: &str) -> bool {
+  !id.is_empty() && id.len() <= MAX_ID_LENGTH && id.chars().all(|c| c.is_ascii_graphic())
+}
+
+/// Middleware function which attaches request ID to request extensions, log lines
+/// emitted while handling the request, response headers and JSON error bodies.
+pub fn wrap<S>(
+  req: ServiceRequest,
+  srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse, Error>>
+where
+  S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error>,
+{
+  let request_id = RequestId::from_request(&req);
+  req.extensions_mut().insert(request_id.clone());
+  // Warn level keeps the span enabled at default verbosity
+  let span = tracing::warn_span!("request", request_id = %request_id);
+  let fut = srv.call(req);
+  async move {
+    match fut.await {
+      Ok(res) => {
+        let json_error = res
+          .response()
+          .error()
+          .and_then(|e| e.as_error::<errors::AppError>())
+          .map(|e| e.as_json_error(Some(&request_id)));
+        let mut res = match json_error {
+          Some(json_error) => res.into_response(json_error),
+          None => res,
+        };
+        insert_header(res.headers_mut(), &request_id);
+        Ok(res)
+      }
+      // Middleware errors, like failed authentication, are turned into responses by the server
+      Err(e) => {
+        let mut res = match e.as_error::<errors::AppError>() {
+          Some(app_error) => app_error.as_json_error(Some(&request_id)),
+          None => e.as_response_error().error_response(),
+        };
+        insert_header(res.headers_mut(), &request_id);
+        Err(InternalError::from_response(e, res).into())
+      }
+    }
+  }
+  .instrument(span)
+}
+
+fn insert_header(headers: &mut HeaderMap, request_id: &RequestId) {
+  if let Ok(value) = HeaderValue::from_str(&request_id.0) {
+    headers.insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+  }
+}
@@ -0,0 +1,85 @@
+//! Structured logging, OpenTelemetry tracing setup and W3C trace context propagation
+
+use crate::config;
+
+use actix_web::http::HeaderMap;
+use anyhow::{Context, Result};
+use log::debug;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::{trace, Resource};
+use opentelemetry::{global, KeyValue};
+use tracing_log::LogTracer;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::{fmt, EnvFilter};
+
+/// Log target of this crate
+const LOG_TARGET: &str = "graph_breaker";
+
+/// Keeps the OTLP exporter running, spans are flushed when dropped
+pub struct TelemetryGuard {
+  _uninstall: Option<opentelemetry_otlp::Uninstall>,
+}
+
+/// Install JSON log output and, if an endpoint is configured, OTLP span exporter.
+/// Records from the `log` crate are forwarded, so they carry fields of the current spans.
+pub fn init(
+  verbosity: log::LevelFilter,
+  settings: &config::TelemetrySettings,
+) -> Result<TelemetryGuard> {
+  global::set_text_map_propagator(TraceContextPropagator::new());
+  LogTracer::init().context("failed to forward log records")?;
+
+  let filter = EnvFilter::new(format!(
+    "actix_web=debug,{}={}",
+    LOG_TARGET,
+    verbosity.to_string().to_lowercase()
+  ));
+  let (otel_layer, uninstall) = match &settings.otlp_endpoint {
+    Some(endpoint) => {
+      let (tracer, uninstall) = opentelemetry_otlp::new_pipeline()
+        .with_endpoint(endpoint)
+        .with_trace_config(trace::config().with_resource(Resource::new(vec![KeyValue::new(
+          "service.name",
+          settings.service_name.clone(),
+        )])))
+        .install()
+        .context(format!("failed to install OTLP exporter for {}", endpoint))?;
+      (
+        Some(tracing_opentelemetry::layer().with_tracer(tracer)),
+        Some(uninstall),
+      )
+    }
+    None => (None, None),
+  };
+  let subscriber = tracing_subscriber::registry()
+    .with(filter)
+    .with(fmt::layer().json())
+    .with(otel_layer);
+  tracing::subscriber::set_global_default(subscriber)
+    .context("failed to set global tracing subscriber")?;
+  if uninstall.is_none() {
+    debug!("No OTLP endpoint configured, spans are not exported");
+  }
+  Ok(TelemetryGuard {
+    _uninstall: uninstall,
+  })
+}
+
+/// Read trace context from incoming request headers
+pub struct HeaderExtractor<'a>(pub &'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+  fn get(&self, key: &str) -> Option<&str> {
+    self.0.get(key).and_then(|value| value.to_str().ok())
+  }
+
+  fn keys(&self) -> Vec<&str> {
+    self.0.keys().map(|key| key.as_str()).collect()
+  }
+}
+
+/// Return parent context propagated by the caller, if any
+pub fn parent_context(headers: &HeaderMap) -> opentelemetry::Context {
+  global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)))
+}
@@ -0,0 +1,395 @@
+//! TLS termination for the HTTP server
+
+use crate::config;
+
+use actix_http::body::MessageBody;
+use actix_http::{Error, HttpService, Request, Response};
+use actix_rt::net::TcpStream;
+use actix_server::ServerBuilder;
+use actix_service::{map_config, IntoServiceFactory, Service, ServiceFactory};
+use actix_tls::rustls::TlsStream;
+use actix_web::dev::AppConfig;
+
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use rustls::internal::pemfile;
+use rustls::sign::{self, CertifiedKey};
+use rustls::{
+  AllowAnyAnonymousOrAuthenticatedClient, Certificate, ClientCertVerified, ClientCertVerifier,
+  DistinguishedNames, NoClientAuth, PrivateKey, ResolvesServerCert, RootCertStore, ServerConfig,
+  Session, SignatureScheme, TLSError,
+};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use std::{fmt, fs, io, net};
+
+/// Client certificate of a connection, stored in request extensions
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientCertificate {
+  /// Whether action endpoints require a certificate, fixed on startup like the listener
+  pub required: bool,
+
+  /// Subject CN of the verified certificate, `None` for clients without one
+  pub common_name: Option<String>,
+}
+
+/// Return the client certificate of a TLS connection
+fn client_certificate(stream: &TlsStream<TcpStream>, required: bool) -> ClientCertificate {
+  let common_name = stream
+    .get_ref()
+    .1
+    .get_peer_certificates()
+    .and_then(|certs| certs.first().and_then(|cert| common_name(&cert.0)));
+  ClientCertificate {
+    required,
+    common_name,
+  }
+}
+
+/// Serve the app over TLS on the listener. Unlike `HttpServer::listen_rustls` this stores
+/// `ClientCertificate` of each connection in request extensions.
+pub fn listen<F, I, S, B>(
+  builder: ServerBuilder,
+  listener: net::TcpListener,
+  config: ServerConfig,
+  client_certificate_required: bool,
+  factory: F,
+) -> io::Result<ServerBuilder>
+where
+  F: Fn() -> I + Send + Clone + 'static,
+  I: IntoServiceFactory<S>,
+  S: ServiceFactory<Config = AppConfig, Request = Request>,
+  S::Error: Into<Error> + 'static,
+  S::InitError: fmt::Debug,
+  S::Response: Into<Response<B>> + 'static,
+  <S::Service as Service>::Future: 'static,
+  B: MessageBody + 'static,
+{
+  builder.listen("graph-breaker-tls", listener, move || {
+    HttpService::build()
+      .on_connect(move |stream: &TlsStream<TcpStream>| {
+        client_certificate(stream, client_certificate_required)
+      })
+      // Scheme and host of requests are taken from headers, the default config isn't used
+      .finish(map_config(factory(), |_| AppConfig::default()))
+      .rustls(config.clone())
+  })
+}
+
+/// Build rustls server config from TLS settings
+pub fn server_config(settings: &config::TlsSettings) -> Result<ServerConfig> {
+  let client_auth = match &settings.client_ca_path {
+    None => NoClientAuth::new(),
+    Some(ca_path) => {
+      let roots = load_root_store(ca_path)?;
+      // Certificates are required on `/action` only, so probes can connect without one
+      Arc::new(CommonNameVerifier {
+        inner: AllowAnyAnonymousOrAuthenticatedClient::new(roots),
+        allowed_cns: settings.client_allowed_cns.clone(),
+      })
+    }
+  };
+  let mut cfg = ServerConfig::new(client_auth);
+  cfg.cert_resolver = Arc::new(ReloadingCertResolver::new(
+    settings.cert_path.clone(),
+    settings.key_path.clone(),
+  )?);
+  Ok(cfg)
+}
+
+fn load_root_store(path: &Path) -> Result<RootCertStore> {
+  let mut store = RootCertStore::empty();
+  for cert in load_certs(path)? {
+    store
+      .add(&cert)
+      .map_err(|e| anyhow!("invalid client CA certificate in {}: {}", path.display(), e))?;
+  }
+  Ok(store)
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+  let f = fs::File::open(path).context(format!("failed to open {}", path.display()))?;
+  let certs = pemfile::certs(&mut io::BufReader::new(f))
+    .map_err(|_| anyhow!("failed to parse certificates in {}", path.display()))?;
+  if certs.is_empty() {
+    bail!("no certificates found in {}", path.display());
+  }
+  Ok(certs)
+}
+
+fn load_private_key(path: &Path) -> Result<PrivateKey> {
+  let content = fs::read(path).context(format!("failed to read {}", path.display()))?;
+  let pkcs8 = pemfile::pkcs8_private_keys(&mut content.as_slice())
+    .map_err(|_| anyhow!("failed to parse PKCS#8 key in {}", path.display()))?;
+  let rsa = pemfile::rsa_private_keys(&mut content.as_slice())
+    .map_err(|_| anyhow!("failed to parse RSA key in {}", path.display()))?;
+  pkcs8
+    .into_iter()
+    .chain(rsa)
+    .next()
+    .ok_or_else(|| anyhow!("no private key found in {}", path.display()))
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey> {
+  let certs = load_certs(cert_path)?;
+  let key = load_private_key(key_path)?;
+  let signing_key = sign::any_supported_type(&key)
+    .map_err(|_| anyhow!("unsupported private key type in {}", key_path.display()))?;
+  Ok(CertifiedKey::new(certs, Arc::new(signing_key)))
+}
+
+/// Return the most recent modification time of the given files
+fn last_modified(paths: &[&Path]) -> Option<SystemTime> {
+  paths
+    .iter()
+    .filter_map(|p| fs::metadata(p).and_then(|m| m.modified()).ok())
+    .max()
+}
+
+struct LoadedCert {
+  key: CertifiedKey,
+  modified: Option<SystemTime>,
+}
+
+/// Certificate resolver which reloads the certificate and key when either file changes
+struct ReloadingCertResolver {
+  cert_path: PathBuf,
+  key_path: PathBuf,
+  loaded: RwLock<LoadedCert>,
+}
+
+impl ReloadingCertResolver {
+  fn new(cert_path: PathBuf, key_path: PathBuf) -> Result<Self> {
+    let modified = last_modified(&[&cert_path, &key_path]);
+    let key = load_certified_key(&cert_path, &key_path)?;
+    Ok(ReloadingCertResolver {
+      cert_path,
+      key_path,
+      loaded: RwLock::new(LoadedCert { key, modified }),
+    })
+  }
+
+  fn reload_if_changed(&self) {
+    let modified = last_modified(&[&self.cert_path, &self.key_path]);
+    if modified == self.loaded.read().unwrap().modified {
+      return;
+    }
+    match load_certified_key(&self.cert_path, &self.key_path) {
+      Ok(key) => {
+        info!("Reloaded TLS certificate {}", self.cert_path.display());
+        *self.loaded.write().unwrap() = LoadedCert { key, modified };
+      }
+      // Files may be mid-rotation, keep serving the previous certificate
+      Err(e) => warn!("Failed to reload TLS certificate: {:#}", e),
+    }
+  }
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+  fn resolve(
+    &self,
+    _server_name: Option<webpki::DNSNameRef>,
+    _sigschemes: &[SignatureScheme],
+  ) -> Option<CertifiedKey> {
+    self.reload_if_changed();
+    Some(self.loaded.read().unwrap().key.clone())
+  }
+}
+
+/// Client certificate verifier which additionally checks the subject CN
+struct CommonNameVerifier {
+  inner: Arc<dyn ClientCertVerifier>,
+  allowed_cns: Vec<String>,
+}
+
+impl ClientCertVerifier for CommonNameVerifier {
+  fn client_auth_mandatory(&self) -> bool {
+    self.inner.client_auth_mandatory()
+  }
+
+  fn client_auth_root_subjects(&self) -> DistinguishedNames {
+    self.inner.client_auth_root_subjects()
+  }
+
+  fn verify_client_cert(
+    &self,
+    presented_certs: &[Certificate],
+  ) -> Result<ClientCertVerified, TLSError> {
+    let verified = self.inner.verify_client_cert(presented_certs)?;
+    let cn = presented_certs
+      .first()
+      .and_then(|cert| common_name(&cert.0))
+      .ok_or_else(|| TLSError::General("client certificate has no common name".to_string()))?;
+    if !self.allowed_cns.is_empty() && !self.allowed_cns.contains(&cn) {
+      return Err(TLSError::General(format!(
+        "client certificate CN {} is not allowed",
+        cn
+      )));
+    }
+    debug!("Accepted client certificate for CN {}", cn);
+    Ok(verified)
+  }
+}
+
+/// Extract subject common name from a DER-encoded certificate
+fn common_name(der: &[u8]) -> Option<String> {
+  let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+  let cn = cert.subject().iter_common_name().next()?;
+  cn.as_str().ok().map(|s| s.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use actix_web::{web, App, HttpRequest, HttpResponse};
+  use std::io::{Read, Write};
+
+  fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+      .join("tests/fixtures/tls")
+      .join(name)
+  }
+
+  fn verifier(allowed_cns: &[&str]) -> CommonNameVerifier {
+    CommonNameVerifier {
+      inner: AllowAnyAnonymousOrAuthenticatedClient::new(
+        load_root_store(&fixture("ca.crt")).unwrap(),
+      ),
+      allowed_cns: allowed_cns.iter().map(|cn| cn.to_string()).collect(),
+    }
+  }
+
+  #[test]
+  fn certificate_common_name() {
+    let certs = load_certs(&fixture("cincinnati-monitor.crt")).unwrap();
+    assert_eq!(
+      common_name(&certs[0].0),
+      Some("cincinnati-monitor".to_string())
+    );
+    let certs = load_certs(&fixture("no-cn.crt")).unwrap();
+    assert_eq!(common_name(&certs[0].0), None);
+    assert_eq!(common_name(b"not a certificate"), None);
+  }
+
+  /// Send a request over TLS, presenting the client certificate if set, and return the response
+  fn get_over_tls(addr: net::SocketAddr, client: Option<&str>) -> io::Result<String> {
+    let mut cfg = rustls::ClientConfig::new();
+    cfg
+      .root_store
+      .add(&load_certs(&fixture("ca.crt")).unwrap()[0])
+      .unwrap();
+    if let Some(name) = client {
+      cfg.set_single_client_cert(
+        load_certs(&fixture(&format!("{}.crt", name))).unwrap(),
+        load_private_key(&fixture(&format!("{}.key", name))).unwrap(),
+      );
+    }
+    let dns_name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut session = rustls::ClientSession::new(&Arc::new(cfg), dns_name);
+    let mut socket = net::TcpStream::connect(addr)?;
+    let mut stream = rustls::Stream::new(&mut session, &mut socket);
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+    let mut response = Vec::new();
+    // Server may close the connection without close_notify once the response is sent
+    let _ = stream.read_to_end(&mut response);
+    Ok(String::from_utf8_lossy(&response).into_owned())
+  }
+
+  #[actix_rt::test]
+  async fn listen_stores_client_certificate() {
+    let settings = config::TlsSettings {
+      cert_path: fixture("server.crt"),
+      key_path: fixture("server.key"),
+      client_ca_path: Some(fixture("ca.crt")),
+      client_allowed_cns: vec![],
+    };
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = listen(
+      actix_server::Server::build().workers(1).disable_signals(),
+      listener,
+      server_config(&settings).unwrap(),
+      true,
+      || {
+        App::new().route(
+          "/",
+          web::get().to(|req: HttpRequest| async move {
+            let certificate = req
+              .extensions()
+              .get::<ClientCertificate>()
+              .cloned()
+              .unwrap_or_default();
+            HttpResponse::Ok().body(format!(
+              "{} {}",
+              certificate.required,
+              certificate.common_name.unwrap_or_default()
+            ))
+          }),
+        )
+      },
+    )
+    .unwrap()
+    .run();
+
+    let response = web::block(move || get_over_tls(addr, Some("cincinnati-monitor")))
+      .await
+      .unwrap();
+    assert!(
+      response.ends_with("\r\n\r\ntrue cincinnati-monitor"),
+      "{}",
+      response
+    );
+    let response = web::block(move || get_over_tls(addr, None)).await.unwrap();
+    assert!(response.ends_with("\r\n\r\ntrue "), "{}", response);
+
+    server.stop(true).await;
+  }
+
+  #[test]
+  fn verify_allowed_common_names() {
+    let monitor = load_certs(&fixture("cincinnati-monitor.crt")).unwrap();
+    let other = load_certs(&fixture("other-client.crt")).unwrap();
+
+    let any_cn = verifier(&[]);
+    assert!(!any_cn.client_auth_mandatory());
+    assert!(any_cn.verify_client_cert(&monitor).is_ok());
+    assert!(any_cn.verify_client_cert(&other).is_ok());
+
+    let allowlist = verifier(&["cincinnati-monitor"]);
+    assert!(allowlist.verify_client_cert(&monitor).is_ok());
+    match allowlist.verify_client_cert(&other) {
+      Err(TLSError::General(msg)) => {
+        assert_eq!(msg, "client certificate CN other-client is not allowed")
+      }
+      Err(e) => panic!("unexpected error: {}", e),
+      Ok(_) => panic!("other-client must not be allowed"),
+    }
+
+    // Not signed by the client CA
+    let self_signed = load_certs(&fixture("no-cn.crt")).unwrap();
+    assert!(any_cn.verify_client_cert(&self_signed).is_err());
+  }
+
+  #[test]
+  fn load_private_keys() {
+    let pkcs8 = load_private_key(&fixture("cincinnati-monitor.key")).unwrap();
+    assert!(sign::any_supported_type(&pkcs8).is_ok());
+    let rsa = load_private_key(&fixture("server-rsa.key")).unwrap();
+    assert!(sign::any_supported_type(&rsa).is_ok());
+
+    let err = load_private_key(&fixture("ca.crt")).unwrap_err();
+    assert!(err.to_string().starts_with("no private key found in "));
+    assert!(load_private_key(&fixture("missing.key")).is_err());
+  }
+
+  #[test]
+  fn load_certified_key_pair() {
+    assert!(load_certified_key(
+      &fixture("cincinnati-monitor.crt"),
+      &fixture("cincinnati-monitor.key")
+    )
+    .is_ok());
+    assert!(load_certified_key(&fixture("server-rsa.key"), &fixture("server-rsa.key")).is_err());
+  }
+}